@@ -11,11 +11,249 @@
 // types have a compile time size due to the Sized trait, and that it are Expression types due
 // to the Expression trait. That's what the val function does.
 
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::parser::Locale;
+
 /// Anything that can evaluate as an expression. Usually, expressions are dealt with
 /// in [Box]es.
-pub trait Expression {
-    /// Evaluates the expression.
-    fn eval(&self) -> f64;
+pub trait Expression: AsAny + IntoExpression {
+    /// Evaluates the expression, looking up the values of variables in the given
+    /// environment. Fails when a variable is not in the environment, or when an
+    /// operation has no result, like division by zero.
+    fn eval_in(&self, env: &HashMap<String, f64>) -> Result<f64, EvalError>;
+
+    /// Evaluates the expression without any variables.
+    fn eval(&self) -> Result<f64, EvalError> {
+        self.eval_in(&HashMap::new())
+    }
+
+    /// Returns true if the expression doesn't depend on any variables, i.e. it
+    /// evaluates to the same value in any environment.
+    fn is_constant(&self) -> bool {
+        true
+    }
+
+    /// Returns true if evaluating this expression is guaranteed not to round. This is a
+    /// conservative check: all literals must be exactly representable, and only `+`, `-`,
+    /// `*` and division by a power of two are allowed. Note that `+`, `-` and `*` can still
+    /// round when the result needs more than the 53 bits of precision of an [f64].
+    fn is_exact(&self) -> bool;
+
+    /// Evaluates the expression like [Expression::eval_in] does, but counts every
+    /// evaluated operator in the given [Profile]. Values that aren't operators are not
+    /// counted.
+    fn eval_profiled(&self, env: &HashMap<String, f64>, _profile: &mut Profile) -> Result<f64, EvalError> {
+        self.eval_in(env)
+    }
+
+    /// How tightly the expression binds when it's written in infix notation, which
+    /// decides whether it needs parentheses as an operand. By default, an expression
+    /// is written as the number it evaluates to, see [Expression::write_infix].
+    fn precedence(&self) -> Precedence {
+        // A negative number is written with a leading '-', so '(-1) ^ 2' needs its
+        // parentheses like a negation does
+        match self.eval() {
+            Ok(n) if n.is_sign_negative() => Precedence::Prefix,
+            _ => Precedence::Atom
+        }
+    }
+
+    /// Writes the expression in infix notation, with only the parentheses that are
+    /// needed to parse it back to the same expression. By default, an expression is
+    /// written as the number it evaluates to, which suits the number types. Integral
+    /// numbers are written without a trailing `.0`, and NaN is written as the `nan`
+    /// constant, since Rust writes it as `NaN`, which would parse as a variable.
+    ///
+    /// Numbers and arguments are written with the conventions of the given [Locale],
+    /// so that the result parses back in that locale.
+    fn write_infix(&self, f: &mut fmt::Formatter, locale: Locale) -> fmt::Result {
+        match self.eval() {
+            Ok(n) if n.is_nan() => write!(f, "nan"),
+            Ok(n) => write!(f, "{}", n.to_string().replace('.', &locale.decimal_separator().to_string())),
+            Err(_) => write!(f, "?")
+        }
+    }
+
+    /// Returns true if the two expressions are the same tree: the same operators, with
+    /// the same operands in the same order. So `1+2` equals `1+2`, but not `2+1`, even
+    /// though they evaluate the same. By default, an expression only equals a value of
+    /// the same type with the same bits, which suits the number types.
+    #[allow(dead_code)] // Not used by the calculator, but useful to anyone using this code
+    fn structural_eq(&self, other: &dyn Expression) -> bool {
+        // Comparing bits makes NaN equal to itself, and 0 different from -0, which is
+        // what we want when comparing trees rather than numbers
+        self.as_any().type_id() == other.as_any().type_id()
+            && matches!((self.eval(), other.eval()), (Ok(a), Ok(b)) if a.to_bits() == b.to_bits())
+    }
+
+    /// Finds where NaN first comes from when evaluating in the given environment: the
+    /// first subexpression, in evaluation order, that evaluates to NaN while none of
+    /// its operands do. Gives [None] if the expression doesn't evaluate to NaN. The
+    /// subexpression is given in infix notation, in the given [Locale].
+    fn nan_origin(&self, env: &HashMap<String, f64>, locale: Locale) -> Option<String> {
+        match self.eval_in(env) {
+            Ok(n) if n.is_nan() => Some(Infix { exp: self, locale }.to_string()),
+            _ => None
+        }
+    }
+
+    /// Returns a simpler expression that evaluates the same: subexpressions without
+    /// variables are folded into their value, and operations that do nothing, like
+    /// `x * 1`, are removed. By default, an expression is already as simple as it gets.
+    fn simplify(self: Box<Self>) -> Box<dyn Expression> {
+        self.into_expression()
+    }
+}
+
+/// Gives access to an expression as [Any], which knows the concrete type behind a
+/// `dyn Expression`. That way, [Expression::structural_eq] can tell if the other
+/// expression is of the same type, and look into it if it is.
+///
+/// This is a separate trait because it can be implemented for every type at once,
+/// which the [Expression] trait can't do in a default method: a default method can't
+/// turn 'self' into a '&dyn Any', since it doesn't know that 'Self' is sized.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+
+    /// Like [AsAny::as_any], but takes the expression out of its box, so it can be
+    /// taken apart.
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// Turns a boxed expression into a `Box<dyn Expression>`, for the same reason as [AsAny]:
+/// the default method [Expression::simplify] can't do that by itself.
+pub trait IntoExpression {
+    fn into_expression(self: Box<Self>) -> Box<dyn Expression>;
+}
+
+impl<T: Expression + 'static> IntoExpression for T {
+    fn into_expression(self: Box<Self>) -> Box<dyn Expression> {
+        self
+    }
+}
+
+// Trait objects can implement traits too, so any boxed expression can be printed with
+// '{}'. Box forwards Display to what it contains. This writes it in the default locale,
+// use [Infix] for another one.
+impl fmt::Display for dyn Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_infix(f, Locale::En)
+    }
+}
+
+// A trait object can have methods of its own too. This can't be a default method of
+// Expression, since then it wouldn't know that 'Self' can be put in an Infix.
+impl dyn Expression {
+    /// Displays the expression in infix notation, in the given [Locale].
+    pub fn infix(&self, locale: Locale) -> Infix<'_, dyn Expression> {
+        Infix { exp: self, locale }
+    }
+}
+
+/// Displays an expression in infix notation in a [Locale], see [Expression::write_infix].
+/// This also works for expressions that aren't boxed.
+pub struct Infix<'a, T: ?Sized> {
+    exp: &'a T,
+    locale: Locale
+}
+
+impl<T: Expression + ?Sized> fmt::Display for Infix<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.exp.write_infix(f, self.locale)
+    }
+}
+
+/// How tightly an expression binds in infix notation, from loosest to tightest. An
+/// operand needs parentheses when it binds looser than its operator allows.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Precedence {
+    /// `+` and `-`.
+    Add,
+
+    /// `*`, `/` and `%`.
+    Mul,
+
+    /// `^`.
+    Pow,
+
+    /// A leading `-`.
+    Prefix,
+
+    /// A trailing `!`.
+    Postfix,
+
+    /// Anything that never needs parentheses, like numbers, variables and function calls.
+    Atom
+}
+
+/// An error that happened while evaluating an expression.
+#[derive(Debug)]
+pub enum EvalError {
+    /// The right operand of `/` or `%` was zero.
+    DivisionByZero,
+
+    /// The variable with this name has no value.
+    UndefinedVariable(String),
+
+    /// An operator or function was given an operand it's not defined for.
+    DomainError(&'static str)
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::DivisionByZero => write!(f, "Division by zero"),
+            EvalError::UndefinedVariable(name) => write!(f, "Variable '{}' is not defined", name),
+            EvalError::DomainError(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+/// Counts how many times each type of operator was evaluated.
+#[derive(Default)]
+pub struct Profile {
+    counts: HashMap<&'static str, usize>
+}
+
+impl Profile {
+    /// Counts one evaluation of the operator with the given name.
+    fn count(&mut self, name: &'static str) {
+        *self.counts.entry(name).or_insert(0) += 1;
+    }
+}
+
+impl fmt::Display for Profile {
+    /// Formats the profile as a list like `Mul: 3, Add: 2`, with the most evaluated
+    /// operator first.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.counts.is_empty() {
+            return write!(f, "No operators");
+        }
+
+        let mut counts: Vec<_> = self.counts.iter().collect();
+        counts.sort_by(|(an, ac), (bn, bc)| bc.cmp(ac).then(an.cmp(bn)));
+
+        for (i, (name, count)) in counts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", name, count)?;
+        }
+        Ok(())
+    }
 }
 
 /// Creates a boxed expression that's a single value. Any sized value that implements
@@ -24,6 +262,11 @@ pub fn val<T: Sized + Expression + 'static>(l: T) -> Box<dyn Expression>  {
     Box::new(l)
 }
 
+/// Creates a boxed expression that's a variable with the given name.
+pub fn var(name: &str) -> Box<dyn Expression> {
+    Box::new(Variable(String::from(name)))
+}
+
 /// Creates a boxed expression that's the sum of two inner expressions.
 pub fn add(l: Box<dyn Expression>, r: Box<dyn Expression>) -> Box<dyn Expression> {
     Box::new(Operator::Add(l, r))
@@ -49,14 +292,229 @@ pub fn rem(l: Box<dyn Expression>, r: Box<dyn Expression>) -> Box<dyn Expression
     Box::new(Operator::Rem(l, r))
 }
 
+/// Creates a boxed expression that's the left expression raised to the power of the right
+/// expression.
+pub fn pow(l: Box<dyn Expression>, r: Box<dyn Expression>) -> Box<dyn Expression> {
+    Box::new(Operator::Pow(l, r))
+}
+
 /// Creates a boxed expression that's the negation of an inner expression.
 pub fn neg(e: Box<dyn Expression>) -> Box<dyn Expression> {
     Box::new(Operator::Neg(e))
 }
 
+/// Creates a boxed expression that's the factorial of an inner expression.
+pub fn factorial(e: Box<dyn Expression>) -> Box<dyn Expression> {
+    Box::new(Operator::Factorial(e))
+}
+
 /// Creates a boxed expression that's the absolute of an inner expression.
 pub fn abs(e: Box<dyn Expression>) -> Box<dyn Expression> {
-    Box::new(Operator::Abs(e))
+    func(FuncKind::Abs, vec![e])
+}
+
+/// Creates a boxed expression that's an inner expression rounded to the nearest integer.
+pub fn round(e: Box<dyn Expression>) -> Box<dyn Expression> {
+    func(FuncKind::Round, vec![e])
+}
+
+/// Creates a boxed expression that's a function applied on inner expressions. There
+/// must be as many expressions as the function takes arguments, see [FuncKind::accepts].
+pub fn func(kind: FuncKind, args: Vec<Box<dyn Expression>>) -> Box<dyn Expression> {
+    Box::new(Operator::Func(kind, args))
+}
+
+/// A mathematical function.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FuncKind {
+    /// The absolute value.
+    Abs,
+
+    /// The sign: -1 for negative numbers, 1 for positive numbers and 0 for zero.
+    Sign,
+
+    /// Rounding to the nearest integer. Halfway cases round away from zero, so 2.5
+    /// becomes 3 and -2.5 becomes -3.
+    Round,
+
+    /// Rounding down, to the largest integer that isn't larger, so -2.5 becomes -3.
+    Floor,
+
+    /// Rounding up, to the smallest integer that isn't smaller, so -2.5 becomes -2.
+    Ceil,
+
+    /// Rounding towards zero, which drops the fraction, so -2.7 becomes -2.
+    Trunc,
+
+    /// The square root. The square root of a negative number is NaN.
+    Sqrt,
+
+    /// The sine, in radians.
+    Sin,
+
+    /// The cosine, in radians.
+    Cos,
+
+    /// The tangent, in radians.
+    Tan,
+
+    /// The unnormalized sinc function, which is `sin(x) / x`, and 1 at 0.
+    Sinc,
+
+    /// The natural logarithm. Like all logarithms here, it's only defined for
+    /// positive numbers, so it's NaN for zero and negative numbers.
+    Ln,
+
+    /// The logarithm with base 10.
+    Log10,
+
+    /// The logarithm with base 2.
+    Log2,
+
+    /// The logarithm with the base given as first argument, of the second argument.
+    /// The base must be positive and not 1, otherwise it's NaN.
+    Log,
+
+    /// The next float after the first argument, in the direction of the second.
+    NextAfter,
+
+    /// The unit in the last place: the gap between a number and the next float
+    /// away from zero.
+    Ulp,
+
+    /// The value of a given rank among the other arguments: `nth(k, ...)` sorts the
+    /// values from small to large and takes the `k`-th one, counting from 1. A rank
+    /// that isn't one of the values gives NaN.
+    Nth,
+
+    /// The smallest of two or more values.
+    Min,
+
+    /// The largest of two or more values.
+    Max,
+
+    /// The first argument restricted to the range from the second to the third. An
+    /// empty range, where the second argument is greater than the third, is an error.
+    Clamp,
+}
+
+/// How a function computes its result from its arguments.
+#[derive(Clone, Copy)]
+enum FuncImpl {
+    /// A function of one argument.
+    Unary(fn(f64) -> f64),
+
+    /// A function of two arguments.
+    Binary(fn(f64, f64) -> f64),
+
+    /// A function of any amount of arguments, with a minimum amount.
+    Variadic(usize, fn(&[f64]) -> f64),
+
+    /// A function of an exact amount of arguments, that fails for some of them.
+    Fallible(usize, fn(&[f64]) -> Result<f64, EvalError>),
+}
+
+impl FuncKind {
+    /// All kinds of functions.
+    pub const ALL: &'static [FuncKind] = &[
+        FuncKind::Abs,
+        FuncKind::Sign,
+        FuncKind::Round,
+        FuncKind::Floor,
+        FuncKind::Ceil,
+        FuncKind::Trunc,
+        FuncKind::Sqrt,
+        FuncKind::Sin,
+        FuncKind::Cos,
+        FuncKind::Tan,
+        FuncKind::Sinc,
+        FuncKind::Ln,
+        FuncKind::Log10,
+        FuncKind::Log2,
+        FuncKind::Log,
+        FuncKind::NextAfter,
+        FuncKind::Ulp,
+        FuncKind::Nth,
+        FuncKind::Min,
+        FuncKind::Max,
+        FuncKind::Clamp,
+    ];
+
+    /// The name of the function, as used in expressions, and the Rust function that
+    /// computes it. Adding a function is a matter of adding a variant and a line here,
+    /// and adding it to [FuncKind::ALL].
+    fn def(self) -> (&'static str, FuncImpl) {
+        match self {
+            FuncKind::Abs => ("abs", FuncImpl::Unary(_abs)),
+            FuncKind::Sign => ("sign", FuncImpl::Unary(_sign)),
+            FuncKind::Round => ("round", FuncImpl::Unary(f64::round)),
+            FuncKind::Floor => ("floor", FuncImpl::Unary(f64::floor)),
+            FuncKind::Ceil => ("ceil", FuncImpl::Unary(f64::ceil)),
+            FuncKind::Trunc => ("trunc", FuncImpl::Unary(f64::trunc)),
+            FuncKind::Sqrt => ("sqrt", FuncImpl::Unary(f64::sqrt)),
+            FuncKind::Sin => ("sin", FuncImpl::Unary(f64::sin)),
+            FuncKind::Cos => ("cos", FuncImpl::Unary(f64::cos)),
+            FuncKind::Tan => ("tan", FuncImpl::Unary(f64::tan)),
+            FuncKind::Sinc => ("sinc", FuncImpl::Unary(_sinc)),
+            FuncKind::Ln => ("ln", FuncImpl::Unary(_ln)),
+            FuncKind::Log10 => ("log10", FuncImpl::Unary(_log10)),
+            FuncKind::Log2 => ("log2", FuncImpl::Unary(_log2)),
+            FuncKind::Log => ("log", FuncImpl::Binary(_log)),
+            FuncKind::NextAfter => ("nextafter", FuncImpl::Binary(_next_after)),
+            FuncKind::Ulp => ("ulp", FuncImpl::Unary(_ulp)),
+            FuncKind::Nth => ("nth", FuncImpl::Variadic(2, _nth)),
+            FuncKind::Min => ("min", FuncImpl::Variadic(2, _min)),
+            FuncKind::Max => ("max", FuncImpl::Variadic(2, _max)),
+            FuncKind::Clamp => ("clamp", FuncImpl::Fallible(3, _clamp)),
+        }
+    }
+
+    /// Finds the function with the given name.
+    pub fn from_name(name: &str) -> Option<FuncKind> {
+        FuncKind::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    /// The name of the function, as used in expressions.
+    pub fn name(self) -> &'static str {
+        self.def().0
+    }
+
+    /// The least amount of arguments the function takes.
+    pub fn min_args(self) -> usize {
+        match self.def().1 {
+            FuncImpl::Unary(_) => 1,
+            FuncImpl::Binary(_) => 2,
+            FuncImpl::Variadic(min, _) => min,
+            FuncImpl::Fallible(n, _) => n,
+        }
+    }
+
+    /// Whether the function takes any amount of arguments from [FuncKind::min_args] on,
+    /// rather than exactly that many.
+    pub fn is_variadic(self) -> bool {
+        matches!(self.def().1, FuncImpl::Variadic(..))
+    }
+
+    /// Whether the function can be called with the given amount of arguments.
+    pub fn accepts(self, n: usize) -> bool {
+        if self.is_variadic() {
+            n >= self.min_args()
+        } else {
+            n == self.min_args()
+        }
+    }
+
+    /// Applies the function on its arguments. Panics if the function doesn't
+    /// [accept](FuncKind::accepts) that amount of arguments.
+    pub fn apply(self, args: &[f64]) -> Result<f64, EvalError> {
+        match (self.def().1, args) {
+            (FuncImpl::Unary(f), [x]) => Ok(f(*x)),
+            (FuncImpl::Binary(f), [x, y]) => Ok(f(*x, *y)),
+            (FuncImpl::Variadic(min, f), args) if args.len() >= min => Ok(f(args)),
+            (FuncImpl::Fallible(n, f), args) if args.len() == n => f(args),
+            _ => panic!("Wrong amount of arguments for {}", self.name())
+        }
+    }
 }
 
 /// An operator expression, which joins two expressions.
@@ -76,28 +534,689 @@ pub enum Operator {
     /// The remainder of two expressions.
     Rem(Box<dyn Expression>, Box<dyn Expression>),
 
+    /// The power of two expressions.
+    Pow(Box<dyn Expression>, Box<dyn Expression>),
+
     /// The negation of two expressions.
     Neg(Box<dyn Expression>),
 
-    /// The absolute of two expressions.
-    Abs(Box<dyn Expression>),
+    /// The factorial of an expression, written as a postfix '!'.
+    Factorial(Box<dyn Expression>),
+
+    /// A function applied on expressions.
+    Func(FuncKind, Vec<Box<dyn Expression>>),
 }
 
 
+impl Operator {
+    /// The name of this type of operator, as used in a [Profile].
+    fn name(&self) -> &'static str {
+        match self {
+            Operator::Add(_, _) => "Add",
+            Operator::Sub(_, _) => "Sub",
+            Operator::Mul(_, _) => "Mul",
+            Operator::Div(_, _) => "Div",
+            Operator::Rem(_, _) => "Rem",
+            Operator::Pow(_, _) => "Pow",
+            Operator::Neg(_) => "Neg",
+            Operator::Factorial(_) => "Factorial",
+            Operator::Func(kind, _) => kind.name()
+        }
+    }
+
+    /// The operands of the operator, in the order they're evaluated.
+    fn operands(&self) -> Vec<&dyn Expression> {
+        match self {
+            Operator::Add(left, right)
+            | Operator::Sub(left, right)
+            | Operator::Mul(left, right)
+            | Operator::Div(left, right)
+            | Operator::Rem(left, right)
+            | Operator::Pow(left, right) => vec![&**left, &**right],
+
+            Operator::Neg(exp)
+            | Operator::Factorial(exp) => vec![&**exp],
+
+            Operator::Func(_, args) => args.iter().map(|arg| &**arg).collect()
+        }
+    }
+
+    /// Writes an operand, in parentheses if it binds looser than `min`.
+    fn write_operand(f: &mut fmt::Formatter, locale: Locale, exp: &dyn Expression, min: Precedence) -> fmt::Result {
+        if exp.precedence() < min {
+            write!(f, "(")?;
+            exp.write_infix(f, locale)?;
+            write!(f, ")")
+        } else {
+            exp.write_infix(f, locale)
+        }
+    }
+
+    /// Writes a left associative binary operator of the given precedence, like `+`
+    /// or `*`.
+    fn write_binary(
+        f: &mut fmt::Formatter,
+        locale: Locale,
+        left: &dyn Expression,
+        symbol: &str,
+        right: &dyn Expression,
+        prec: Precedence
+    ) -> fmt::Result {
+        // Left associative operators need parentheses for an operand of the same
+        // precedence on the right: '1 - (2 - 3)' is not '1 - 2 - 3'
+        let right_min = match prec {
+            Precedence::Add => Precedence::Mul,
+            _ => Precedence::Pow
+        };
+        Operator::write_operand(f, locale, left, prec)?;
+        write!(f, " {} ", symbol)?;
+        Operator::write_operand(f, locale, right, right_min)
+    }
+}
+
 impl Expression for Operator {
-    fn eval(&self) -> f64 {
+    // Operands are always evaluated from left to right: the left operand is evaluated
+    // completely before the right one. Rust guarantees that the operands of an operator
+    // like 'left.eval_in(env)? + right.eval_in(env)?' are evaluated in that order, so when
+    // both operands fail, the error of the left one is reported. It also matters once
+    // expressions can have side effects (like drawing random numbers), so keep it that way
+    // in all evaluators.
+    fn eval_in(&self, env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        match self {
+            Operator::Add(left, right) => Ok(left.eval_in(env)? + right.eval_in(env)?),
+            Operator::Sub(left, right) => Ok(left.eval_in(env)? - right.eval_in(env)?),
+            Operator::Mul(left, right) => Ok(left.eval_in(env)? * right.eval_in(env)?),
+            Operator::Div(left, right) => _div(left.eval_in(env)?, right.eval_in(env)?),
+            Operator::Rem(left, right) => _rem(left.eval_in(env)?, right.eval_in(env)?),
+            Operator::Pow(left, right) => Ok(left.eval_in(env)?.powf(right.eval_in(env)?)),
+            Operator::Neg(exp) => Ok(-exp.eval_in(env)?),
+            Operator::Factorial(exp) => _factorial(exp.eval_in(env)?),
+            Operator::Func(kind, args) => {
+                // Collecting into a Result stops at the first error
+                let args = args.iter().map(|arg| arg.eval_in(env)).collect::<Result<Vec<_>, _>>()?;
+                kind.apply(&args)
+            }
+        }
+    }
+
+    fn is_constant(&self) -> bool {
+        match self {
+            Operator::Add(left, right)
+            | Operator::Sub(left, right)
+            | Operator::Mul(left, right)
+            | Operator::Div(left, right)
+            | Operator::Rem(left, right)
+            | Operator::Pow(left, right) => left.is_constant() && right.is_constant(),
+
+            Operator::Neg(exp)
+            | Operator::Factorial(exp) => exp.is_constant(),
+            Operator::Func(_, args) => args.iter().all(|arg| arg.is_constant())
+        }
+    }
+
+    fn is_exact(&self) -> bool {
+        match self {
+            Operator::Add(left, right) => left.is_exact() && right.is_exact(),
+            Operator::Sub(left, right) => left.is_exact() && right.is_exact(),
+            Operator::Mul(left, right) => left.is_exact() && right.is_exact(),
+
+            // Dividing by a power of two only changes the exponent, any other
+            // divisor may give an infinite binary fraction, like 1/3 does
+            Operator::Div(left, right) => {
+                left.is_exact() && right.is_exact() && right.eval().is_ok_and(_is_power_of_two)
+            },
+
+            // Not one of the operations we check for
+            Operator::Rem(_, _) => false,
+            Operator::Pow(_, _) => false,
+            Operator::Factorial(_) => false,
+
+            // These only change the sign
+            Operator::Neg(exp) => exp.is_exact(),
+            Operator::Func(FuncKind::Abs, args) => args[0].is_exact(),
+
+            // An integer is always exact, but if the operand was rounded it
+            // may be rounded to the wrong integer
+            Operator::Func(FuncKind::Round | FuncKind::Floor | FuncKind::Ceil | FuncKind::Trunc, args) => args[0].is_exact(),
+
+            // Not one of the operations we check for
+            Operator::Func(_, _) => false
+        }
+    }
+
+    fn eval_profiled(&self, env: &HashMap<String, f64>, profile: &mut Profile) -> Result<f64, EvalError> {
+        profile.count(self.name());
+
+        match self {
+            Operator::Add(left, right) => Ok(left.eval_profiled(env, profile)? + right.eval_profiled(env, profile)?),
+            Operator::Sub(left, right) => Ok(left.eval_profiled(env, profile)? - right.eval_profiled(env, profile)?),
+            Operator::Mul(left, right) => Ok(left.eval_profiled(env, profile)? * right.eval_profiled(env, profile)?),
+            Operator::Div(left, right) => _div(left.eval_profiled(env, profile)?, right.eval_profiled(env, profile)?),
+            Operator::Rem(left, right) => _rem(left.eval_profiled(env, profile)?, right.eval_profiled(env, profile)?),
+            Operator::Pow(left, right) => Ok(left.eval_profiled(env, profile)?.powf(right.eval_profiled(env, profile)?)),
+            Operator::Neg(exp) => Ok(-exp.eval_profiled(env, profile)?),
+            Operator::Factorial(exp) => _factorial(exp.eval_profiled(env, profile)?),
+            Operator::Func(kind, args) => {
+                let args = args.iter().map(|arg| arg.eval_profiled(env, profile)).collect::<Result<Vec<_>, _>>()?;
+                kind.apply(&args)
+            }
+        }
+    }
+
+    fn precedence(&self) -> Precedence {
         match self {
-            Operator::Add(left, right) => left.eval() + right.eval(),
-            Operator::Sub(left, right) => left.eval() - right.eval(),
-            Operator::Mul(left, right) => left.eval() * right.eval(),
-            Operator::Div(left, right) => left.eval() / right.eval(),
-            Operator::Rem(left, right) => left.eval() % right.eval(),
-            Operator::Neg(exp) => -exp.eval(),
-            Operator::Abs(exp) => _abs(exp.eval())
+            Operator::Add(_, _) | Operator::Sub(_, _) => Precedence::Add,
+            Operator::Mul(_, _) | Operator::Div(_, _) | Operator::Rem(_, _) => Precedence::Mul,
+            Operator::Pow(_, _) => Precedence::Pow,
+            Operator::Neg(_) => Precedence::Prefix,
+            Operator::Factorial(_) => Precedence::Postfix,
+            Operator::Func(_, _) => Precedence::Atom
         }
     }
+
+    fn write_infix(&self, f: &mut fmt::Formatter, locale: Locale) -> fmt::Result {
+        match self {
+            Operator::Add(left, right) => Operator::write_binary(f, locale, &**left, "+", &**right, Precedence::Add),
+            Operator::Sub(left, right) => Operator::write_binary(f, locale, &**left, "-", &**right, Precedence::Add),
+            Operator::Mul(left, right) => Operator::write_binary(f, locale, &**left, "*", &**right, Precedence::Mul),
+            Operator::Div(left, right) => Operator::write_binary(f, locale, &**left, "/", &**right, Precedence::Mul),
+            Operator::Rem(left, right) => Operator::write_binary(f, locale, &**left, "%", &**right, Precedence::Mul),
+
+            // The grammar reads '-2 ^ 2' as '-(2 ^ 2)', so the left operand of '^' can't
+            // have a leading '-'. The right operand may be another power, as '^' is
+            // right associative.
+            Operator::Pow(left, right) => {
+                Operator::write_operand(f, locale, &**left, Precedence::Postfix)?;
+                write!(f, " ^ ")?;
+                Operator::write_operand(f, locale, &**right, Precedence::Pow)
+            },
+
+            // A leading '-' applies to a whole power
+            Operator::Neg(exp) => {
+                write!(f, "-")?;
+                Operator::write_operand(f, locale, &**exp, Precedence::Pow)
+            },
+
+            Operator::Factorial(exp) => {
+                Operator::write_operand(f, locale, &**exp, Precedence::Postfix)?;
+                write!(f, "!")
+            },
+
+            Operator::Func(kind, args) => {
+                write!(f, "{}(", kind.name())?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{} ", locale.argument_separator())?;
+                    }
+                    arg.write_infix(f, locale)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+
+    fn structural_eq(&self, other: &dyn Expression) -> bool {
+        let other = match other.as_any().downcast_ref::<Operator>() {
+            Some(other) => other,
+            None => return false
+        };
+
+        match (self, other) {
+            (Operator::Add(a, b), Operator::Add(c, d))
+            | (Operator::Sub(a, b), Operator::Sub(c, d))
+            | (Operator::Mul(a, b), Operator::Mul(c, d))
+            | (Operator::Div(a, b), Operator::Div(c, d))
+            | (Operator::Rem(a, b), Operator::Rem(c, d))
+            | (Operator::Pow(a, b), Operator::Pow(c, d)) => a.structural_eq(&**c) && b.structural_eq(&**d),
+
+            (Operator::Neg(a), Operator::Neg(b))
+            | (Operator::Factorial(a), Operator::Factorial(b)) => a.structural_eq(&**b),
+
+            (Operator::Func(kind_a, args_a), Operator::Func(kind_b, args_b)) => {
+                kind_a == kind_b
+                    && args_a.len() == args_b.len()
+                    && args_a.iter().zip(args_b).all(|(a, b)| a.structural_eq(&**b))
+            },
+
+            // Different types of operators
+            _ => false
+        }
+    }
+
+    fn nan_origin(&self, env: &HashMap<String, f64>, locale: Locale) -> Option<String> {
+        // If an operand is NaN, that's where it comes from. Otherwise, it's this
+        // operator, if it's NaN at all. An operand that fails to evaluate means the
+        // whole operator fails, so it isn't NaN.
+        for operand in self.operands() {
+            match operand.eval_in(env) {
+                Ok(n) if n.is_nan() => return operand.nan_origin(env, locale),
+                Ok(_) => {},
+                Err(_) => return None
+            }
+        }
+
+        match self.eval_in(env) {
+            Ok(n) if n.is_nan() => Some(Infix { exp: self, locale }.to_string()),
+            _ => None
+        }
+    }
+
+    fn simplify(self: Box<Self>) -> Box<dyn Expression> {
+        // Simplify the operands first, so that operands without variables are
+        // single values by now
+        let op = match *self {
+            Operator::Add(left, right) => Operator::Add(left.simplify(), right.simplify()),
+            Operator::Sub(left, right) => Operator::Sub(left.simplify(), right.simplify()),
+            Operator::Mul(left, right) => Operator::Mul(left.simplify(), right.simplify()),
+            Operator::Div(left, right) => Operator::Div(left.simplify(), right.simplify()),
+            Operator::Rem(left, right) => Operator::Rem(left.simplify(), right.simplify()),
+            Operator::Pow(left, right) => Operator::Pow(left.simplify(), right.simplify()),
+            Operator::Neg(exp) => Operator::Neg(exp.simplify()),
+            Operator::Factorial(exp) => Operator::Factorial(exp.simplify()),
+            Operator::Func(kind, args) => Operator::Func(kind, args.into_iter().map(|arg| arg.simplify()).collect())
+        };
+
+        // Without variables, the whole operator folds into its value. If it fails to
+        // evaluate, we keep it, so that it still fails when it's evaluated later.
+        if op.is_constant() {
+            if let Ok(n) = op.eval() {
+                return val(n);
+            }
+        }
+
+        match op {
+            // Sums and products combine all their constants, wherever they are
+            op @ (Operator::Add(_, _) | Operator::Sub(_, _) | Operator::Neg(_)) => _simplify_sum(op),
+            op @ Operator::Mul(_, _) => _simplify_product(op),
+
+            Operator::Div(left, right) if _is_value(&*right, 1.0) => left,
+
+            op => Box::new(op)
+        }
+    }
+}
+
+/// Simplifies a sum of any amount of terms, like `x + 2 - y + 3`, by adding up all
+/// terms without variables into one. The result has the other terms in the same order,
+/// followed by the constant, so this becomes `x - y + 5`. A constant of zero is left
+/// out, and a positive constant goes first when the first other term is subtracted, so
+/// we get `2 - x` rather than `-x + 2`. Note that adding the constants in a different
+/// order may round differently.
+fn _simplify_sum(op: Operator) -> Box<dyn Expression> {
+    let mut terms = Vec::new();
+    _flatten_sum(Box::new(op), false, &mut terms);
+
+    let mut constant = 0.0;
+    let mut rest = Vec::new();
+    for (negative, term) in terms {
+        // A constant that fails to evaluate stays, so it still fails later
+        if let Some(n) = term.is_constant().then(|| term.eval().ok()).flatten() {
+            constant += if negative { -n } else { n };
+        } else {
+            rest.push((negative, term));
+        }
+    }
+
+    let mut res: Option<Box<dyn Expression>> = None;
+    if constant > 0.0 && rest.first().is_some_and(|(negative, _)| *negative) {
+        res = Some(val(constant));
+        constant = 0.0;
+    }
+
+    for (negative, term) in rest {
+        res = Some(match (res, negative) {
+            (None, false) => term,
+            (None, true) => neg(term),
+            (Some(exp), false) => add(exp, term),
+            (Some(exp), true) => sub(exp, term)
+        });
+    }
+
+    match res {
+        None => val(constant),
+        Some(exp) if constant == 0.0 => exp,
+        Some(exp) if constant < 0.0 => sub(exp, val(-constant)),
+        Some(exp) => add(exp, val(constant))
+    }
+}
+
+/// Takes a sum apart into its terms, each with whether it's subtracted. This looks
+/// through any nesting of `+`, `-` and negation, so `x - (y - 2)` gives the terms `x`,
+/// `-y` and `2`.
+fn _flatten_sum(exp: Box<dyn Expression>, negative: bool, terms: &mut Vec<(bool, Box<dyn Expression>)>) {
+    // We can only take the operator out of its box once we know it's one we want,
+    // otherwise we have no way back to a 'Box<dyn Expression>'. Note the '*': the box
+    // itself is also a type that AsAny is implemented for, and without it we'd get the
+    // box as Any rather than what's in it.
+    let is_sum = matches!(
+        (*exp).as_any().downcast_ref::<Operator>(),
+        Some(Operator::Add(_, _) | Operator::Sub(_, _) | Operator::Neg(_))
+    );
+    if !is_sum {
+        terms.push((negative, exp));
+        return;
+    }
+
+    match exp.into_any().downcast::<Operator>().map(|op| *op) {
+        Ok(Operator::Add(left, right)) => {
+            _flatten_sum(left, negative, terms);
+            _flatten_sum(right, negative, terms);
+        },
+        Ok(Operator::Sub(left, right)) => {
+            _flatten_sum(left, negative, terms);
+            _flatten_sum(right, !negative, terms);
+        },
+        Ok(Operator::Neg(exp)) => _flatten_sum(exp, !negative, terms),
+        _ => unreachable!("Checked to be a sum above")
+    }
+}
+
+/// Simplifies a product of any amount of factors, like `2 * x * 3 * y`, by multiplying
+/// all factors without variables into one. The result has the constant first, followed
+/// by the other factors in the same order, so this becomes `6 * x * y`. A constant of 1
+/// is left out, and a constant of 0 makes the whole product 0.
+fn _simplify_product(op: Operator) -> Box<dyn Expression> {
+    let mut factors = Vec::new();
+    _flatten_product(Box::new(op), &mut factors);
+
+    let mut constant = 1.0;
+    let mut rest = Vec::new();
+    for factor in factors {
+        if let Some(n) = factor.is_constant().then(|| factor.eval().ok()).flatten() {
+            constant *= n;
+        } else {
+            rest.push(factor);
+        }
+    }
+
+    // Note that this isn't entirely the same: when another factor is infinity or NaN,
+    // or fails to evaluate, the product isn't 0
+    if constant == 0.0 || rest.is_empty() {
+        return val(constant);
+    }
+
+    let mut rest = rest.into_iter();
+    let first = rest.next().unwrap();
+    let mut res = if constant == 1.0 {
+        first
+    } else if constant == -1.0 {
+        neg(first)
+    } else {
+        mul(val(constant), first)
+    };
+    for factor in rest {
+        res = mul(res, factor);
+    }
+    res
+}
+
+/// Takes a product apart into its factors, looking through any nesting of `*`.
+fn _flatten_product(exp: Box<dyn Expression>, factors: &mut Vec<Box<dyn Expression>>) {
+    if !matches!((*exp).as_any().downcast_ref::<Operator>(), Some(Operator::Mul(_, _))) {
+        factors.push(exp);
+        return;
+    }
+
+    match exp.into_any().downcast::<Operator>().map(|op| *op) {
+        Ok(Operator::Mul(left, right)) => {
+            _flatten_product(left, factors);
+            _flatten_product(right, factors);
+        },
+        _ => unreachable!("Checked to be a product above")
+    }
+}
+
+
+/// A variable, which evaluates to the value that its name has in the environment.
+pub struct Variable(pub String);
+
+impl Expression for Variable {
+    fn eval_in(&self, env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        env.get(&self.0).copied().ok_or_else(|| EvalError::UndefinedVariable(self.0.clone()))
+    }
+
+    fn is_constant(&self) -> bool {
+        false
+    }
+
+    fn is_exact(&self) -> bool {
+        // We don't know the value, so we can't tell
+        false
+    }
+
+    fn precedence(&self) -> Precedence {
+        Precedence::Atom
+    }
+
+    fn write_infix(&self, f: &mut fmt::Formatter, _locale: Locale) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+
+    fn structural_eq(&self, other: &dyn Expression) -> bool {
+        other.as_any().downcast_ref::<Variable>().is_some_and(|other| other.0 == self.0)
+    }
+}
+
+
+/// Returns true if the expression is the given value. After simplifying, an operand
+/// without variables is a single value, so this is all we have to check.
+fn _is_value(exp: &dyn Expression, n: f64) -> bool {
+    exp.is_constant() && exp.eval().is_ok_and(|v| v == n)
+}
+
+/// Division, which fails instead of giving infinity when dividing by zero.
+fn _div(l: f64, r: f64) -> Result<f64, EvalError> {
+    if r == 0.0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    Ok(l / r)
+}
+
+/// Remainder, which fails instead of giving NaN when dividing by zero.
+fn _rem(l: f64, r: f64) -> Result<f64, EvalError> {
+    if r == 0.0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    Ok(l % r)
+}
+
+/// Returns the float that comes right after `x` in the direction of `y`. If the two are
+/// equal, that's `y`, and if either is NaN, it's NaN. Note that the next float after
+/// the largest finite float is infinity.
+fn _next_after(x: f64, y: f64) -> f64 {
+    if x.is_nan() || y.is_nan() {
+        return f64::NAN;
+    }
+    if x == y {
+        return y;
+    }
+
+    // Both zeros are next to the smallest subnormal float of either sign, whose bit
+    // pattern is just the lowest mantissa bit
+    if x == 0.0 {
+        let tiny = f64::from_bits(1);
+        return if y > 0.0 { tiny } else { -tiny };
+    }
+
+    // Floats are stored as sign and magnitude, and the bit patterns of the magnitudes
+    // are in the same order as the magnitudes themselves. So the next float away from
+    // zero has the next bit pattern, and the next float towards zero the previous one.
+    // This also steps correctly between subnormal and normal floats, and from the
+    // largest float to infinity.
+    let bits = x.to_bits();
+    if (y > x) == (x > 0.0) {
+        f64::from_bits(bits + 1)
+    } else {
+        f64::from_bits(bits - 1)
+    }
+}
+
+/// Computes the factorial `n!` of a non-negative integer `n`, which is the product of
+/// all integers from 1 to `n`.
+fn _factorial(n: f64) -> Result<f64, EvalError> {
+    // Note that NaN and infinity have no fraction, so they fail this check too
+    if n < 0.0 || n.fract() != 0.0 {
+        return Err(EvalError::DomainError("Factorial is only defined for non-negative integers"));
+    }
+
+    // 171! is too large for a float, there's no need to multiply 10^18 numbers to
+    // find that out
+    if n > 170.0 {
+        return Ok(f64::INFINITY);
+    }
+
+    let mut res = 1.0;
+    let mut i = 2.0;
+    while i <= n {
+        res *= i;
+        i += 1.0;
+    }
+    Ok(res)
+}
+
+/// The sinc function, `sin(x) / x`. At zero, that would be 0 divided by 0, but the
+/// closer `x` gets to zero, the closer `sin(x) / x` gets to 1, so that's what we give.
+fn _sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        x.sin() / x
+    }
+}
+
+/// The logarithm of `x` with base `base`. The base comes first, like it's written in
+/// math, which is the other way around than [f64::log]. Gives NaN where the logarithm
+/// isn't defined: for an `x` that isn't positive, and for a base that isn't positive
+/// or is 1.
+fn _log(base: f64, x: f64) -> f64 {
+    // Rust gives negative infinity for the logarithm of 0, and the logarithm with
+    // base 1 divides by the logarithm of 1, which is 0
+    if x <= 0.0 || base <= 0.0 || base == 1.0 {
+        return f64::NAN;
+    }
+
+    // Rust computes other bases as 'ln(x) / ln(base)', which rounds twice, so that
+    // log(10, 1000) would be a bit less than 3. For the common bases there are exact
+    // functions.
+    if base == 2.0 {
+        x.log2()
+    } else if base == 10.0 {
+        x.log10()
+    } else if base == std::f64::consts::E {
+        x.ln()
+    } else {
+        x.log(base)
+    }
+}
+
+/// The natural logarithm, see [_log].
+fn _ln(x: f64) -> f64 {
+    _log(std::f64::consts::E, x)
+}
+
+/// The logarithm with base 10, see [_log].
+fn _log10(x: f64) -> f64 {
+    _log(10.0, x)
+}
+
+/// The logarithm with base 2, see [_log].
+fn _log2(x: f64) -> f64 {
+    _log(2.0, x)
+}
+
+/// Returns the gap between `x` and the next float away from zero, so `ulp(1)` is
+/// [f64::EPSILON]. The sign of `x` doesn't matter.
+fn _ulp(x: f64) -> f64 {
+    let x = _abs(x);
+    if !x.is_finite() {
+        // NaN stays NaN, and there's no float after infinity
+        return x;
+    }
+
+    // The float after the largest one is infinity, but the gap to the float before it
+    // has the same size
+    if x == f64::MAX {
+        return x - _next_after(x, 0.0);
+    }
+
+    // Two neighbouring floats are so close that their difference is exact. Near zero,
+    // this is the smallest subnormal float.
+    _next_after(x, f64::INFINITY) - x
+}
+
+/// Takes the value of rank `args[0]` among the rest of the arguments, counting from 1
+/// for the smallest. Ranks that aren't a whole number from 1 to the amount of values
+/// give NaN.
+fn _nth(args: &[f64]) -> f64 {
+    let (k, values) = match args.split_first() {
+        Some(split) => split,
+        None => return f64::NAN
+    };
+
+    if k.fract() != 0.0 || *k < 1.0 || *k > values.len() as f64 {
+        return f64::NAN;
+    }
+
+    // Sort a copy, total_cmp puts NaN after everything else so that sorting never
+    // fails
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    sorted[*k as usize - 1]
 }
 
+/// The smallest of the arguments. Unlike [f64::min], a NaN anywhere gives NaN, since we
+/// can't tell what's smallest if one of the values is unknown.
+fn _min(args: &[f64]) -> f64 {
+    let mut res = f64::INFINITY;
+    for &x in args {
+        if x.is_nan() {
+            return x;
+        }
+        if x < res {
+            res = x;
+        }
+    }
+    res
+}
+
+/// The largest of the arguments. Like [_min], a NaN anywhere gives NaN.
+fn _max(args: &[f64]) -> f64 {
+    let mut res = f64::NEG_INFINITY;
+    for &x in args {
+        if x.is_nan() {
+            return x;
+        }
+        if x > res {
+            res = x;
+        }
+    }
+    res
+}
+
+/// The sign of `n`. Unlike [f64::signum], zero gives 0, not 1, and NaN stays NaN.
+fn _sign(n: f64) -> f64 {
+    if n < 0.0 {
+        -1.0
+    } else if n > 0.0 {
+        1.0
+    } else {
+        // Zero or NaN, both are their own sign
+        n
+    }
+}
+
+/// Restricts `args[0]` to the range from `args[1]` to `args[2]`, see [clamp]. An empty
+/// range gives an error, since there's no value we could give.
+fn _clamp(args: &[f64]) -> Result<f64, EvalError> {
+    let [v, lo, hi] = *args else {
+        panic!("Wrong amount of arguments for clamp");
+    };
+
+    if lo > hi {
+        return Err(EvalError::DomainError("The lower bound of clamp must not be greater than the upper bound"));
+    }
+    Ok(clamp(v, lo, hi))
+}
 
 /// Absolute value function.
 fn _abs(n: f64) -> f64 {
@@ -109,6 +1228,121 @@ fn _abs(n: f64) -> f64 {
 }
 
 
+/// Restricts a value to the range from `lo` to `hi`. Values below `lo` give `lo`, values
+/// above `hi` give `hi`, and NaN stays NaN. The range must not be empty, i.e. `lo` must
+/// not be greater than `hi`.
+pub fn clamp(v: f64, lo: f64, hi: f64) -> f64 {
+    if v < lo {
+        lo
+    } else if v > hi {
+        hi
+    } else {
+        v
+    }
+}
+
+/// Linearly interpolates between `a` and `b`: `t = 0` gives exactly `a`, `t = 1` gives
+/// exactly `b`. Other values of `t` are not clamped, so they extrapolate.
+#[allow(dead_code)] // Not used by the calculator, but useful to anyone using this code
+pub fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    // Computing 'a + (b - a) * t' is cheaper, but may not give exactly 'b' at 't = 1'
+    // due to rounding
+    (1.0 - t) * a + t * b
+}
+
+/// Returns true if the two expressions evaluate to about the same value for random
+/// values of the given variables, in `trials` tries. This is a quick way to check that
+/// two expressions are the same function, like `(x+1)*(x+1)` and `x*x + 2*x + 1`,
+/// without proving it. Values are from -10 to 10. When both expressions fail to
+/// evaluate for the same values, they agree.
+#[allow(dead_code)] // Not used by the calculator, but useful to anyone using this code
+pub fn numerically_equal(a: &dyn Expression, b: &dyn Expression, vars: &[&str], trials: usize) -> bool {
+    // Always start with the same seed, so that the outcome doesn't change between runs
+    let mut random = Random(0x2545F4914F6CDD1D);
+    let mut env = HashMap::new();
+
+    for _ in 0..trials {
+        for name in vars {
+            env.insert(String::from(*name), random.next_f64() * 20.0 - 10.0);
+        }
+
+        match (a.eval_in(&env), b.eval_in(&env)) {
+            (Ok(x), Ok(y)) if _about_equal(x, y) => {},
+            (Err(_), Err(_)) => {},
+            _ => return false
+        }
+    }
+    true
+}
+
+/// Returns true if two results are equal, apart from rounding errors. Rewriting an
+/// expression often changes how it rounds, so exact equality is too strict.
+fn _about_equal(x: f64, y: f64) -> bool {
+    // This also covers equal infinities, which can't be subtracted
+    if x == y || (x.is_nan() && y.is_nan()) {
+        return true;
+    }
+
+    // Relative to the size of the numbers, but near zero the difference itself counts
+    _abs(x - y) <= 1e-9 * _abs(x).max(_abs(y)).max(1.0)
+}
+
+/// A simple pseudorandom number generator, xorshift64. It's not good enough for
+/// anything where randomness really matters, but it's fast and needs no dependencies.
+struct Random(u64);
+
+impl Random {
+    /// Returns the next pseudorandom number from 0 (inclusive) to 1 (exclusive).
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+
+        // A float has 53 bits of precision, so take the highest 53 bits and divide
+        // them by 2^53
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Returns true if the number is a (positive or negative) power of two.
+fn _is_power_of_two(n: f64) -> bool {
+    // A normal float with no mantissa bits set is exactly a power of two
+    n.is_normal() && n.to_bits() & ((1 << 52) - 1) == 0
+}
+
+/// Returns true if the number is exactly the decimal number it prints as. A literal
+/// like `0.1` can't be stored exactly as a float, it's rounded to the nearest one. The
+/// fraction digits of a decimal number are some integer `d` over `10^k`, which is only
+/// a binary fraction if `d` is a multiple of `5^k`.
+fn _is_exact_decimal(n: f64) -> bool {
+    if !n.is_finite() {
+        return false;
+    }
+
+    // Every integer up to 2^53 is a float, like for the integer types. Above that,
+    // there are gaps between floats, so a literal there may have been rounded to the
+    // nearest one. Unlike the integer types, we only have that float, and 2^53 itself
+    // is also what 9007199254740993 rounds to, so it doesn't count either.
+    if n.fract() == 0.0 {
+        return n.abs() < (1u64 << 53) as f64;
+    }
+
+    // Rust prints floats with the shortest amount of digits needed to parse back to
+    // the same float. If that decimal number is exactly the float, then so was any
+    // literal of up to 17 significant digits that gave the same float.
+    let st = n.to_string();
+    let frac = match st.split_once('.') {
+        Some((_, frac)) => frac,
+        None => return true
+    };
+
+    // A float has at most 17 significant digits, so d is always less than 5^25
+    // and it can't be a multiple when there are more digits than that
+    let k = frac.len() as u32;
+    let d: u128 = frac.parse().unwrap();
+    k < 25 && d.is_multiple_of(5u128.pow(k))
+}
+
 
 // All numeric values are expressions that evaluate to themselves as f64. Rust
 // allows you to add traits to existing types. Since numbers are 'Sized', the
@@ -117,77 +1351,125 @@ fn _abs(n: f64) -> f64 {
 
 // Floats
 impl Expression for f64 {
-    fn eval(&self) -> f64 {
-        *self
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self)
+    }
+
+    fn is_exact(&self) -> bool {
+        _is_exact_decimal(*self)
     }
 }
 
 impl Expression for f32 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        _is_exact_decimal(*self as f64)
     }
 }
 
 
 // Signed integers
 impl Expression for i8 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        true
     }
 }
 
 impl Expression for i16 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        true
     }
 }
 
 impl Expression for i32 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        true
     }
 }
 
 impl Expression for i64 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        self.unsigned_abs() <= 1 << 53
     }
 }
 
 impl Expression for i128 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        self.unsigned_abs() <= 1 << 53
     }
 }
 
 
 // Unsigned integers
 impl Expression for u8 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        true
     }
 }
 
 impl Expression for u16 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        true
     }
 }
 
 impl Expression for u32 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        true
     }
 }
 
 impl Expression for u64 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        *self <= 1 << 53
     }
 }
 
 impl Expression for u128 {
-    fn eval(&self) -> f64 {
-        *self as f64
+    fn eval_in(&self, _env: &HashMap<String, f64>) -> Result<f64, EvalError> {
+        Ok(*self as f64)
+    }
+
+    fn is_exact(&self) -> bool {
+        *self <= 1 << 53
     }
 }
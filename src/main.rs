@@ -1,11 +1,37 @@
 mod expression;
 mod parser;
+mod polynomial;
+mod repl;
 
+use std::env::args;
 use std::io::{stdin, stdout, Write};
 
 use crate::parser::*;
+use crate::repl::{char_index, format_parse_error, Repl};
 
 fn main() {
+    // Check the command line flags. The first argument is the name of
+    // the program itself, so we skip it.
+    let mut summary = false;
+    for arg in args().skip(1) {
+        match arg.as_str() {
+            "--summary" => summary = true,
+            "--version" => {
+                println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                return;
+            },
+            other => {
+                eprintln!("Unknown argument: {}", other);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Counters for the summary
+    let mut evaluated = 0;
+    let mut errors = 0;
+
+    let mut repl = Repl::default();
     let mut ln = String::new();
 
     // Infinite loop
@@ -13,30 +39,105 @@ fn main() {
         // Clear line
         ln.clear();
 
-        // Write >>>, then flush so it appears in the terminal
-        print!(">>> ");
+        // Write the prompt, then flush so it appears in the terminal
+        print!("{}", repl.prompt);
         stdout().flush().expect("Failed to flush stdout");
 
-        // Read input line
-        stdin().read_line(&mut ln).expect("Failed to read input");
+        // Read input line. Reading nothing at all means the input has ended, e.g.
+        // when the end of a piped file is reached or when Ctrl+D is pressed in a
+        // terminal. That's different from an empty line, which still has its newline.
+        // A last line without newline is read normally, the end is noticed on the
+        // next read.
+        if stdin().read_line(&mut ln).expect("Failed to read input") == 0 {
+            println!("Goodbye");
+            break;
+        }
+
+        // Lines starting with ':' are commands for the REPL. A failed command counts
+        // as an error too, but a successful one isn't an evaluated line.
+        if let Some(cmd) = ln.trim_start().strip_prefix(':') {
+            if let Err(x) = repl.command(cmd) {
+                errors += 1;
+                println!("!!! {}", x);
+            }
+            continue;
+        }
+
+        // Entering nothing but spaces terminates the program
+        if is_empty_input(&ln) {
+            println!("Goodbye");
+            break;
+        }
+
+        // Expand macros first, from here on we work with the expanded line
+        let expanded = match repl.expand_macros(&ln) {
+            Ok(expanded) => expanded,
+            Err(x) => {
+                errors += 1;
+                println!("!!! {}", x);
+                continue;
+            }
+        };
 
         // Parse input line, let parser borrow our string
-        match parse(&ln) {
-            // Syntax error, print error
-            ParseResult::Error(x, i) => {
-                println!("!!! {}, at index {}", x, i)
+        let (res, warnings) = parse_with_warnings(&expanded, &repl.parse_options());
+
+        // Print any warnings before the result
+        for w in warnings {
+            println!("??? {}, at index {}", w.message, char_index(&expanded, w.span.start));
+        }
+
+        match res {
+            // Syntax error, print error under the input
+            ParseResult::Error(x) => {
+                errors += 1;
+                println!("{}", format_parse_error(&expanded, &x))
             },
 
-            // Empty input, exit
+            // Only empty input is absent, and we checked for that already
             ParseResult::Absent => {
-                println!("Goodbye");
-                break;
+                errors += 1;
+                println!("!!! Expected an expression")
             },
 
             // Successful parse, evaluate and print
             ParseResult::Present(exp) => {
-                println!("<<< {}", exp.eval())
+                // Show how the input was understood, written back with only the
+                // parentheses that are needed
+                if repl.echo {
+                    println!("=== {}", exp.infix(repl.locale()));
+                }
+
+                match exp.eval_in(repl.env()) {
+                    Ok(res) => {
+                        evaluated += 1;
+                        println!("<<< {}", repl.format_result(res));
+
+                        // Point out where a NaN came from, it's often not obvious. Only
+                        // search when asked to, the search evaluates parts of the
+                        // expression again.
+                        if repl.trace {
+                            if let Some(origin) = exp.nan_origin(repl.env(), repl.locale()) {
+                                println!("    NaN first comes from {}", origin);
+                            }
+                        }
+
+                        repl.record(&ln, res);
+                    },
+
+                    // Runtime error, like division by zero
+                    Err(x) => {
+                        errors += 1;
+                        println!("!!! runtime error: {}", x)
+                    }
+                }
             }
         }
     }
+
+    // The summary goes to stderr, so that it doesn't end up in the
+    // results when stdout is piped somewhere
+    if summary {
+        eprintln!("# {} evaluated, {} errors", evaluated, errors);
+    }
 }
@@ -1,4 +1,7 @@
-use std::str::Chars;
+use std::error;
+use std::fmt;
+use std::ops::Range;
+use std::str::{Chars, FromStr};
 
 use crate::expression::*;
 
@@ -9,9 +12,8 @@ use MulOp::*;
 use AddOp::*;
 
 /// A parser, which keeps track of the parsing position in the string.
-
 struct Parser<'str> {
-    /// The current index in the string.
+    /// The byte index of the current character in the string.
     idx: usize,
 
     /// The string being parsed.
@@ -24,9 +26,120 @@ struct Parser<'str> {
     str: Chars<'str>,
 
     /// The current character.
-    cur: Option<char>
+    cur: Option<char>,
+
+    /// The amount of operators parsed so far.
+    nodes: usize,
+
+    /// How many parser steps are running right now, one inside the other.
+    depth: usize,
+
+    /// The options to parse with.
+    options: &'str ParseOptions<'str>
+}
+
+/// Options that change how the parser behaves.
+pub struct ParseOptions<'res> {
+    /// The maximum amount of operators in an expression. Larger expressions give an
+    /// error, so that very long inputs can't make us use huge amounts of memory. A
+    /// chain like `1+1+1+...` is as deep as it's long, and evaluating, printing and
+    /// dropping an expression recurse into its operands, so this also has to be small
+    /// enough for the stack to hold an expression of this depth.
+    pub max_nodes: usize,
+
+    /// The maximum amount of parser steps running inside each other. Parentheses,
+    /// prefix operators, powers and function calls make the parser recurse, each
+    /// level takes a few steps. Deeper input gives an error rather than overflowing
+    /// the stack. Note that parentheses and `+` don't count as operators, so
+    /// [ParseOptions::max_nodes] doesn't limit them.
+    pub max_depth: usize,
+
+    /// Earlier results, which can be referred to as `$1`, `$2`, etc.
+    pub results: &'res [f64],
+
+    /// Whether to print every step of the parser to stderr.
+    pub debug: bool,
+
+    /// What to do with a call to a function that doesn't exist.
+    pub unknown_call: UnknownCall,
+
+    /// How numbers and arguments are written.
+    pub locale: Locale
+}
+
+/// What the parser does with a call to a function that doesn't exist, like `foo(3)`.
+#[derive(Clone, Copy)]
+pub enum UnknownCall {
+    /// Give an error.
+    Error,
+
+    /// If the name is a constant, multiply it with the parenthesized expression, so
+    /// `pi(2)` is `pi * (2)`. Other names still give an error.
+    Multiply
+}
+
+/// The conventions for writing numbers and separating the arguments of a function.
+#[derive(Clone, Copy)]
+pub enum Locale {
+    /// Numbers like `1.5`, and arguments separated by commas: `nextafter(1.5, 2)`.
+    En,
+
+    /// Numbers like `1,5`. The comma is taken, so arguments are separated by
+    /// semicolons: `nextafter(1,5; 2)`.
+    Eu
+}
+
+impl Locale {
+    /// The character between the integer and fraction digits of a number.
+    pub fn decimal_separator(self) -> char {
+        match self {
+            Locale::En => '.',
+            Locale::Eu => ','
+        }
+    }
+
+    /// The character between the arguments of a function.
+    pub fn argument_separator(self) -> char {
+        match self {
+            Locale::En => ',',
+            Locale::Eu => ';'
+        }
+    }
+}
+
+impl Default for ParseOptions<'_> {
+    fn default() -> Self {
+        ParseOptions {
+            // Measured in a debug build with the default 8 MiB stack, which needs
+            // more stack than a release build. Evaluating, printing and simplifying a
+            // chain like '1+1+...' overflowed at about 4200 operators. The parser
+            // overflowed at about 5800 steps, for calls like 'sqrt(sqrt(...))' nested
+            // about 1160 deep, at five steps per call. Both defaults leave about half
+            // of that.
+            max_nodes: 2_000,
+            max_depth: 2_500,
+            results: &[],
+            debug: false,
+            unknown_call: UnknownCall::Error,
+            locale: Locale::En
+        }
+    }
 }
 
+/// The named constants that can be used in expressions.
+pub const CONSTANTS: &[(&str, f64)] = &[
+    ("pi", std::f64::consts::PI),
+    ("e", std::f64::consts::E),
+    ("tau", std::f64::consts::TAU),
+
+    // The golden ratio, (1 + sqrt(5)) / 2. Rust doesn't have this constant yet, and
+    // we can't compute a square root in a constant.
+    ("phi", 1.618033988749895),
+
+    ("inf", f64::INFINITY),
+    ("nan", f64::NAN),
+];
+
 /// A parse result.
 pub enum ParseResult {
     /// The parse result that indicates that an expression has been successfully parsed.
@@ -36,12 +149,143 @@ pub enum ParseResult {
     Absent,
 
     /// The parse result that indicates that the input has an incorrect syntax.
-    Error(String, usize)
+    Error(ParseError)
 }
 
+/// A syntax error in the input. Each error has the span of the characters it's about,
+/// as byte indices, so that `&input[err.span()]` is the text it's about.
+#[derive(Debug)]
+pub enum ParseError {
+    /// There is more input after a complete expression.
+    ExtraInput(Range<usize>),
+
+    /// The expression has more operators than [ParseOptions::max_nodes].
+    TooLarge(Range<usize>),
+
+    /// The expression nests deeper than [ParseOptions::max_depth].
+    TooDeep(Range<usize>),
+
+    /// A number has a digit that isn't one of the ASCII digits 0-9.
+    ForeignDigit(Range<usize>),
+
+    /// A number is only decimal separators, like '.'.
+    LoneSeparator(char, Range<usize>),
 
-/// Parses an expression from a string.
-pub fn parse(s: &String) -> ParseResult {
+    /// An underscore in a number that isn't between two digits, like in '1__000'.
+    MisplacedUnderscore(Range<usize>),
+
+    /// A number can't be read as a float, like '1.2.3'.
+    InvalidNumber(Range<usize>),
+
+    /// A '$' isn't followed by the number of a result.
+    ExpectedResultNumber(Range<usize>),
+
+    /// There is no earlier result with the number, as it was written.
+    NoSuchResult(String, Range<usize>),
+
+    /// A call to a function that doesn't exist.
+    UnknownFunction(Range<usize>),
+
+    /// A call to a function with the wrong amount of arguments.
+    ArgumentCount(FuncKind, Range<usize>),
+
+    /// An expression is missing, like after an operator.
+    ExpectedExpression(Range<usize>),
+
+    /// A closing character is missing, like ')'.
+    ExpectedToken(char, Range<usize>),
+
+    /// Neither the argument separator, which is in here, nor a ')' follows an
+    /// argument of a function.
+    ExpectedSeparator(char, Range<usize>)
+}
+
+impl ParseError {
+    /// The span of the characters that the error is about, as byte indices in the
+    /// input. It's empty when something is missing at the end of the input.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            ParseError::ExtraInput(i)
+            | ParseError::TooLarge(i)
+            | ParseError::TooDeep(i)
+            | ParseError::ForeignDigit(i)
+            | ParseError::LoneSeparator(_, i)
+            | ParseError::MisplacedUnderscore(i)
+            | ParseError::InvalidNumber(i)
+            | ParseError::ExpectedResultNumber(i)
+            | ParseError::NoSuchResult(_, i)
+            | ParseError::UnknownFunction(i)
+            | ParseError::ArgumentCount(_, i)
+            | ParseError::ExpectedExpression(i)
+            | ParseError::ExpectedToken(_, i)
+            | ParseError::ExpectedSeparator(_, i) => i.clone()
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    /// Formats the message of the error, without the span.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::ExtraInput(_) => write!(f, "Extra input"),
+            ParseError::TooLarge(_) => write!(f, "Expression too large"),
+            ParseError::TooDeep(_) => write!(f, "Expression nested too deeply"),
+            ParseError::ForeignDigit(_) => write!(f, "Only the digits 0-9 can be used in numbers"),
+            ParseError::LoneSeparator(ch, _) => write!(f, "A number cannot be just '{}'", ch),
+            ParseError::MisplacedUnderscore(_) => write!(f, "Underscores can only be between digits"),
+            ParseError::InvalidNumber(_) => write!(f, "Incorrect number"),
+            ParseError::ExpectedResultNumber(_) => write!(f, "Expected the number of a result after '$'"),
+            ParseError::NoSuchResult(n, _) => write!(f, "There is no result ${}", n),
+            ParseError::UnknownFunction(_) => write!(f, "Unknown function"),
+            ParseError::ArgumentCount(kind, _) => {
+                let plural = if kind.min_args() == 1 { "" } else { "s" };
+                let least = if kind.is_variadic() { "at least " } else { "" };
+                write!(f, "Function '{}' takes {}{} argument{}", kind.name(), least, kind.min_args(), plural)
+            },
+            ParseError::ExpectedExpression(_) => write!(f, "Expected an expression"),
+            ParseError::ExpectedToken(ch, _) => write!(f, "Expected '{}'", ch),
+            ParseError::ExpectedSeparator(ch, _) => write!(f, "Expected '{}' or ')'", ch)
+        }
+    }
+}
+
+
+/// Parses an expression from a string, with the default [ParseOptions]. This crate
+/// only has a binary, which doc tests don't run for, so this example isn't tested:
+///
+/// ```ignore
+/// use crate::parser::{parse, ParseResult};
+///
+/// match parse("1+1") {
+///     ParseResult::Present(exp) => assert_eq!(exp.eval().unwrap(), 2.0),
+///     _ => panic!("Expected an expression")
+/// }
+/// ```
+#[allow(dead_code)] // The REPL parses with its own options, but we want this anyway
+pub fn parse(s: &str) -> ParseResult {
+    parse_with(s, &ParseOptions::default())
+}
+
+/// Parses and evaluates a constant expression in one step, like a default value in a
+/// configuration file. Gives [None] if the expression is invalid or empty, if it uses
+/// any variable, or if it fails to evaluate.
+#[allow(dead_code)] // Not used by the calculator, but useful to anyone using this code
+pub fn try_eval_const(s: &str) -> Option<f64> {
+    match parse(s) {
+        Present(exp) if exp.is_constant() => exp.eval().ok(),
+        _ => None
+    }
+}
+
+/// Parses an expression from a string, with the given [ParseOptions].
+pub fn parse_with(s: &str, options: &ParseOptions) -> ParseResult {
+    _parse_with(s, options).0
+}
+
+/// Parses an expression like [parse_with] does, and also gives the byte index where
+/// parsing stopped. For a [Present] result, that's the end of the line, which may be
+/// after some trailing spaces.
+fn _parse_with(s: &str, options: &ParseOptions) -> (ParseResult, usize) {
     // About the lifetime of the parser and the string, Rust can verify, because we received
     // the string as a parameter, that it lives as long as the parser value lives. This makes
     // perfect sense, the parser value only lives within this method, the string value lives
@@ -54,7 +298,10 @@ pub fn parse(s: &String) -> ParseResult {
     let mut parser: Parser = Parser {
         idx: 0,
         str: s.chars(),
-        cur: None
+        cur: None,
+        nodes: 0,
+        depth: 0,
+        options
     };
 
     parser.cur = parser.str.next();
@@ -65,8 +312,8 @@ pub fn parse(s: &String) -> ParseResult {
     let res = match parser.parse_add() {
         Present(x) => Present(x),
         Absent => Absent,
-        Error(x, i) => {
-            return Error(x, i);
+        Error(x) => {
+            return (Error(x), parser.idx);
         }
     };
 
@@ -74,12 +321,59 @@ pub fn parse(s: &String) -> ParseResult {
     parser.skip_space();
 
     // No remaining input? Fine.
-    if parser.peek() == None {
-        return res;
+    if parser.peek().is_none() {
+        return (res, parser.idx);
     }
 
     // Remaining input is a syntax error.
-    return Error(String::from("Extra input"), parser.idx);
+    (Error(ParseError::ExtraInput(parser.here())), parser.idx)
+}
+
+
+/// Returns true if the input has nothing to parse: it's empty, or only has spaces and
+/// tabs until the end or the first newline. Parsing such input gives [Absent], which
+/// it never does for other input.
+pub fn is_empty_input(s: &str) -> bool {
+    s.chars()
+        .take_while(|ch| *ch != '\n' && *ch != '\r')
+        .all(|ch| _is_space(Some(ch)))
+}
+
+
+/// A warning about an input that is valid, but looks suspicious. Warnings don't
+/// prevent evaluation.
+pub struct Warning {
+    /// The message explaining what is suspicious.
+    pub message: String,
+
+    /// The range of byte indices in the input that the warning is about.
+    pub span: Range<usize>
+}
+
+/// Parses an expression from a string, like [parse], and also checks the input
+/// for things that are valid but suspicious.
+pub fn parse_with_warnings(s: &str, options: &ParseOptions) -> (ParseResult, Vec<Warning>) {
+    let (res, stop) = _parse_with(s, options);
+    let mut warnings = Vec::new();
+
+    if let Present(exp) = &res {
+        // The span of the whole expression, without surrounding spaces. Spaces are
+        // one byte each, and anything after the line wasn't parsed.
+        let line = &s[..stop];
+        let start = line.len() - line.trim_start_matches([' ', '\t']).len();
+        let end = line.trim_end_matches([' ', '\t']).len();
+
+        // If there are no variables in the expression and it evaluates to NaN once,
+        // it will always do that
+        if exp.is_constant() && exp.eval().is_ok_and(f64::is_nan) {
+            warnings.push(Warning {
+                message: String::from("Expression always evaluates to NaN"),
+                span: start..end
+            });
+        }
+    }
+
+    (res, warnings)
 }
 
 
@@ -129,26 +423,17 @@ impl ParseResult {
 
     /// Returns true when the result is present.
     pub fn is_present(&self) -> bool {
-        match self {
-            Present(_) => true,
-            _ => false
-        }
+        matches!(self, Present(_))
     }
 
     /// Returns true when the result is absent.
     pub fn is_absent(&self) -> bool {
-        match self {
-            Absent => true,
-            _ => false
-        }
+        matches!(self, Absent)
     }
 
     /// Returns true when the result is an error.
     pub fn is_error(&self) -> bool {
-        match self {
-            Error(_, _) => true,
-            _ => false
-        }
+        matches!(self, Error(_))
     }
 
     /// Returns an [Option] with the parsed expression, if it is [Present].
@@ -159,10 +444,10 @@ impl ParseResult {
         }
     }
 
-    /// Returns an [Option] with the error message, if it is an [Error] result.
-    pub fn error(self) -> Option<String> {
+    /// Returns an [Option] with the error, if it is an [Error] result.
+    pub fn error(self) -> Option<ParseError> {
         match self {
-            Error(x, _) => Some(x),
+            Error(x) => Some(x),
             _ => None
         }
     }
@@ -170,13 +455,69 @@ impl ParseResult {
     /// Returns an [Option] with the error index, if it is an [Error] result.
     pub fn error_index(self) -> Option<usize> {
         match self {
-            Error(_, x) => Some(x),
+            Error(x) => Some(x.span().start),
             _ => None
         }
     }
 }
 
 
+/// A parsed expression. Unlike a `Box<dyn Expression>`, this can be parsed with the
+/// standard [str::parse] function. Like the example of [parse], this one isn't tested:
+///
+/// ```ignore
+/// use crate::parser::Expr;
+///
+/// let exp: Expr = "1+2*3".parse().unwrap();
+/// assert_eq!(exp.0.eval().unwrap(), 7.0);
+/// ```
+#[allow(dead_code)] // Not used by the calculator, but useful to anyone using this code
+pub struct Expr(pub Box<dyn Expression>);
+
+/// An error from parsing an [Expr].
+#[derive(Debug)]
+#[allow(dead_code)] // Not used by the calculator, but useful to anyone using this code
+pub enum ExprError {
+    /// The input has nothing to parse, see [is_empty_input].
+    EmptyInput,
+
+    /// The input has a syntax error.
+    Syntax(ParseError)
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::EmptyInput => write!(f, "Expected an expression"),
+            ExprError::Syntax(x) => write!(f, "{}, at index {}", x, x.span().start)
+        }
+    }
+}
+
+// Implementing Error lets the '?' operator turn an ExprError into a 'Box<dyn Error>'.
+// It only needs Debug and Display, the methods it has all have defaults.
+impl error::Error for ExprError {}
+
+impl FromStr for Expr {
+    type Err = ExprError;
+
+    /// Parses an expression with the default [ParseOptions].
+    fn from_str(s: &str) -> Result<Expr, ExprError> {
+        // Check for empty input ourselves rather than trusting every Absent result
+        // to mean that
+        if is_empty_input(s) {
+            return Err(ExprError::EmptyInput);
+        }
+
+        match parse(s) {
+            Present(exp) => Ok(Expr(exp)),
+            Absent => Err(ExprError::Syntax(ParseError::ExpectedExpression(0..0))),
+            Error(x) => Err(ExprError::Syntax(x))
+        }
+    }
+}
+
+
 /// A multiplication operator.
 enum MulOp {
     Mul,
@@ -210,14 +551,45 @@ impl<'str> Parser<'str> {
         })
     }
 
+    /// The span of the current character, which is empty at the end of the input.
+    fn here(&self) -> Range<usize> {
+        match self.peek() {
+            Some(ch) => self.idx..self.idx + ch.len_utf8(),
+            None => self.idx..self.idx
+        }
+    }
+
     /// Skips a character.
     fn skip(&mut self) -> &mut Self { // Returns itself, the Self type ensures that
+        // Characters outside of ASCII take more than one byte
+        self.idx += self.cur.map_or(0, char::len_utf8);
         self.cur = self.str.next();
-        self.idx += 1;
 
         self
     }
 
+    /// Counts an operator against the node budget. This is done as soon as we find the
+    /// operator, before parsing its operands, so that we stop right away once the expression
+    /// has become too large. Returns an [Error] result in that case.
+    fn count_node(&mut self) -> Option<ParseResult> {
+        self.nodes += 1;
+
+        if self.nodes > self.options.max_nodes {
+            Some(Error(ParseError::TooLarge(self.here())))
+        } else {
+            None
+        }
+    }
+
+    /// Turns an absent operand into an error at the current position. An operator needs
+    /// its operand, so without one, the input can't be valid.
+    fn required(&self, res: ParseResult) -> ParseResult {
+        match res {
+            Absent => Error(ParseError::ExpectedExpression(self.here())),
+            other => other
+        }
+    }
+
     /// Skips any spaces and then peeks the next character.
     fn symbol(&mut self) -> Option<char> {
         self.skip_space();
@@ -231,37 +603,266 @@ impl<'str> Parser<'str> {
         }
     }
 
+    /// Parses a base expression in the input, see [Parser::base_rules].
+    fn parse_base(&mut self) -> ParseResult {
+        self.step("base", Self::base_rules)
+    }
+
+    /// Parses a postfix expression in the input, see [Parser::postfix_rules].
+    fn parse_postfix(&mut self) -> ParseResult {
+        self.step("postfix", Self::postfix_rules)
+    }
+
+    /// Parses a power expression in the input, see [Parser::pow_rules].
+    fn parse_pow(&mut self) -> ParseResult {
+        self.step("pow", Self::pow_rules)
+    }
+
+    /// Parses a multiplication expression in the input, see [Parser::mul_rules].
+    fn parse_mul(&mut self) -> ParseResult {
+        self.step("mul", Self::mul_rules)
+    }
+
+    /// Parses an addition expression in the input, see [Parser::add_rules].
+    fn parse_add(&mut self) -> ParseResult {
+        self.step("add", Self::add_rules)
+    }
+
+    /// Runs one step of the parser, which is one of the '_rules' functions. In debug
+    /// mode, this prints when the step starts and ends to stderr, along with the
+    /// current index and character.
+    fn step(&mut self, name: &str, rules: fn(&mut Self) -> ParseResult) -> ParseResult {
+        // Every way the parser recurses goes through here, so this is where we
+        // stop before the stack runs out
+        if self.depth >= self.options.max_depth {
+            return Error(ParseError::TooDeep(self.here()));
+        }
+
+        self.depth += 1;
+        let res = self.run_step(name, rules);
+        self.depth -= 1;
+        res
+    }
+
+    /// Runs the rules of a step, printing it in debug mode, see [Parser::step].
+    fn run_step(&mut self, name: &str, rules: fn(&mut Self) -> ParseResult) -> ParseResult {
+        if !self.options.debug {
+            return rules(self);
+        }
+
+        eprintln!("[debug] > {} at index {}, {:?}", name, self.idx, self.peek());
+        let res = rules(self);
+
+        let outcome = match &res {
+            Present(_) => "present",
+            Absent => "absent",
+            Error(_) => "error"
+        };
+        eprintln!("[debug] < {} at index {}, {:?}: {}", name, self.idx, self.peek(), outcome);
+
+        res
+    }
+
     /// Parses a number in the input.
     /// A number has the syntax:
     /// 
     /// ```txt
     /// number:
-    /// 1.  /[0-9.]+/
+    /// 1.  /[0-9._]+/
     /// ```
+    /// 
+    /// Only ASCII digits are allowed. The '.' is the decimal separator of the
+    /// [Locale], which may be a ',' instead. Underscores can separate groups of
+    /// digits, like in '1_000_000', but only between two digits.
     fn parse_number(&mut self) -> ParseResult {
         self.skip_space();
 
         let c = self.peek();
         let s = self.idx;
+        let decimal = self.options.locale.decimal_separator();
 
-        // If no number present, return Absent
-        if !_is_number_char(c) {
+        // Numeric characters that aren't ASCII digits are not supported
+        if _is_foreign_digit(c) {
+            return Error(ParseError::ForeignDigit(self.here()));
+        }
+
+        // If no number present, return Absent. A leading underscore is no
+        // variable name, so we read it as a badly written number.
+        if !_is_number_char(c, decimal) && c != Some('_') {
             return Absent;
         }
 
-        // Keep reading digits and periods until there are no more
+        // Keep reading digits and separators until there are no more. Rust only
+        // parses floats with a period, so that's what we put in the string.
         let mut st = String::new();
         let mut c = self.peek();
-        while _is_number_char(c) {
-            st.push(c.unwrap());
+        while _is_number_char(c, decimal) || c == Some('_') {
+            st.push(if c == Some(decimal) { '.' } else { c.unwrap() });
             c = self.skip().peek();
         }
 
+        // Every underscore needs a digit on both sides, after that we don't need
+        // them anymore
+        let digits: Vec<char> = st.chars().collect();
+        for (i, ch) in digits.iter().enumerate() {
+            let between = i > 0
+                && digits[i - 1].is_ascii_digit()
+                && digits.get(i + 1).is_some_and(char::is_ascii_digit);
+
+            if *ch == '_' && !between {
+                return Error(ParseError::MisplacedUnderscore(s + i..s + i + 1));
+            }
+        }
+        let st = st.replace('_', "");
+
+        // Same goes for the middle of a number
+        if _is_foreign_digit(c) {
+            return Error(ParseError::ForeignDigit(self.here()));
+        }
+
+        // Only periods and no digits, Rust would parse a lone '.' as an
+        // incorrect number too but we can give a better message here
+        if st.chars().all(|ch| ch == '.') {
+            return Error(ParseError::LoneSeparator(decimal, s..self.idx));
+        }
+
         // Parse the number as float, if it fails the syntax is
         // incorrect and we give an Error result
         match st.parse::<f64>() {
             Ok(v) => Present(val(v)),
-            Err(_) => Error(String::from("Incorrect number"), s)
+            Err(_) => Error(ParseError::InvalidNumber(s..self.idx))
+        }
+    }
+
+    /// Parses a reference to an earlier result in the input, after the '$'.
+    /// A result reference has the syntax:
+    /// 
+    /// ```txt
+    /// result:
+    /// 1.  '$' /[0-9]+/
+    /// ```
+    fn parse_result(&mut self) -> ParseResult {
+        // The index of the '$'
+        let s = self.idx;
+
+        // Read the number of the result, which are just digits
+        let mut st = String::new();
+        let mut c = self.skip().peek();
+        while let Some(ch) = c.filter(char::is_ascii_digit) {
+            st.push(ch);
+            c = self.skip().peek();
+        }
+
+        if st.is_empty() {
+            return Error(ParseError::ExpectedResultNumber(s..self.idx));
+        }
+
+        // Results are numbered from 1, like they are shown in the ':results' table
+        match st.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= self.options.results.len() => {
+                Present(val(self.options.results[n - 1]))
+            },
+            _ => Error(ParseError::NoSuchResult(st, s..self.idx))
+        }
+    }
+
+    /// Parses an identifier in the input, which is the name of a constant, a variable or
+    /// a function call. An identifier has the syntax:
+    /// 
+    /// ```txt
+    /// identifier:
+    /// 1.  /[a-zA-Z][a-zA-Z0-9]*/
+    /// 2.  /[a-zA-Z][a-zA-Z0-9]*/ '(' arguments ')'
+    /// ```
+    fn parse_identifier(&mut self) -> ParseResult {
+        let s = self.idx;
+
+        // Keep reading letters and digits until there are no more. The first
+        // character is always a letter, or we wouldn't be here. Note that we read
+        // the whole name before looking it up, so 'pie' is an unknown identifier
+        // rather than 'pi' followed by 'e', and 'log10' is one name.
+        let mut st = String::new();
+        let mut c = self.peek();
+        while let Some(ch) = c.filter(char::is_ascii_alphanumeric) {
+            st.push(ch);
+            c = self.skip().peek();
+        }
+        let name = s..self.idx;
+
+        let constant = CONSTANTS.iter().find(|(name, _)| *name == st);
+
+        // Rule 2, a name followed by '(' is a function call
+        if self.symbol() == Some('(') {
+            if let Some(kind) = FuncKind::from_name(&st) {
+                if let Some(err) = self.count_node() {
+                    return err;
+                }
+
+                return match self.parse_arguments() {
+                    Ok(args) if kind.accepts(args.len()) => Present(func(kind, args)),
+                    Ok(_) => Error(ParseError::ArgumentCount(kind, s..self.idx)),
+                    Err(x) => Error(x)
+                };
+            }
+
+            // Not a function we know
+            return match (self.options.unknown_call, constant) {
+                (UnknownCall::Multiply, Some((_, v))) => {
+                    if let Some(err) = self.count_node() {
+                        return err;
+                    }
+
+                    // Parse the parenthesized expression like it's the right
+                    // hand side of a multiplication, so that pi(2)^2 is pi*(2^2)
+                    self.parse_pow().map(*v, |v, exp| {
+                        mul(val(v), exp)
+                    })
+                },
+
+                _ => Error(ParseError::UnknownFunction(name))
+            };
+        }
+
+        // Rule 1, any name that isn't a constant is a variable
+        match constant {
+            Some((_, v)) => Present(val(*v)),
+            None => Present(var(&st))
+        }
+    }
+
+    /// Parses the arguments of a function call, which are expressions separated by
+    /// commas, between parentheses. The current character must be the '('.
+    ///
+    /// ```txt
+    /// arguments:
+    /// 1.  add
+    /// 2.  arguments ',' add
+    /// ```
+    ///
+    /// The ',' is the argument separator of the [Locale], which may be a ';' instead.
+    fn parse_arguments(&mut self) -> Result<Vec<Box<dyn Expression>>, ParseError> {
+        self.skip();
+        let separator = self.options.locale.argument_separator();
+
+        let mut args = Vec::new();
+        loop {
+            // Unlike a parenthesized expression, an argument can't be left out
+            match self.parse_add() {
+                Present(exp) => args.push(exp),
+                Absent => return Err(ParseError::ExpectedExpression(self.here())),
+                Error(x) => return Err(x)
+            }
+
+            match self.symbol() {
+                Some(')') => {
+                    self.skip();
+                    return Ok(args);
+                },
+                Some(ch) if ch == separator => {
+                    self.skip();
+                },
+                _ => return Err(ParseError::ExpectedSeparator(separator, self.here()))
+            }
         }
     }
 
@@ -271,36 +872,48 @@ impl<'str> Parser<'str> {
     /// ```txt
     /// base:
     /// 1.  number
-    /// 2.  '-' base
-    /// 3.  '+' base
+    /// 2.  '-' pow
+    /// 3.  '+' pow
     /// 4.  '(' add ')'
     /// 5.  '|' add '|'
+    /// 6.  result
+    /// 7.  '~' pow
+    /// 8.  identifier
     /// ```
-    fn parse_base(&mut self) -> ParseResult {
+    fn base_rules(&mut self) -> ParseResult {
         match self.symbol() {
             // Rule 2
             Some('-') => {
-                // The - operator negates the expression
-                self.skip().parse_base().map(self, |_, exp| {
+                if let Some(err) = self.count_node() {
+                    return err;
+                }
+
+                // The - operator negates the expression. It takes a power
+                // as operand, so that -3^2 is -(3^2) like in maths.
+                let res = self.skip().parse_pow();
+                self.required(res).map(self, |_, exp| {
                     neg(exp)
                 })
             },
 
             // Rule 3
             Some('+') => {
-                // The - operator negates the expression
-                self.skip().parse_base().map(self, |_, exp| {
-                    neg(exp)
-                })
+                // The + operator does nothing, just return the expression
+                // unchanged
+                let res = self.skip().parse_pow();
+                self.required(res)
             },
 
             // Rule 4
             Some('(') => {
-                self.skip().parse_add().monad(self, |p, exp| {
-                    // Expect a closing ')'
+                let s = self.idx;
+                let res = self.skip().parse_add();
+                self.required(res).monad(self, |p, exp| {
+                    // Expect a closing ')', the error spans from the '(' that
+                    // it should close
                     p.skip_space();
-                    if p.peek().map_or(true, |ch| ch != ')') {
-                        Error(String::from("Expected ')'"), p.idx)
+                    if p.peek() != Some(')') {
+                        Error(ParseError::ExpectedToken(')', s..p.idx))
                     } else {
                         p.skip();
     
@@ -314,11 +927,17 @@ impl<'str> Parser<'str> {
 
             // Rule 5
             Some('|') => {
-                self.skip().parse_add().monad(self, |p, exp| {
-                    // Expect a closint '|'
+                if let Some(err) = self.count_node() {
+                    return err;
+                }
+
+                let s = self.idx;
+                let res = self.skip().parse_add();
+                self.required(res).monad(self, |p, exp| {
+                    // Expect a closing '|'
                     p.skip_space();
-                    if p.peek().map_or(true, |ch| ch != '|') {
-                        Error(String::from("Expected '|'"), p.idx)
+                    if p.peek() != Some('|') {
+                        Error(ParseError::ExpectedToken('|', s..p.idx))
                     } else {
                         p.skip();
     
@@ -328,58 +947,153 @@ impl<'str> Parser<'str> {
                 })
             },
 
+            // Rule 6
+            Some('$') => self.parse_result(),
+
+            // Rule 7
+            Some('~') => {
+                if let Some(err) = self.count_node() {
+                    return err;
+                }
+
+                // The ~ operator rounds the expression to the nearest integer
+                let res = self.skip().parse_pow();
+                self.required(res).map(self, |_, exp| {
+                    round(exp)
+                })
+            },
+
+            // Rule 8
+            Some(ch) if ch.is_ascii_alphabetic() => self.parse_identifier(),
+
             // Rule 1
             _ => self.parse_number()
         }
     }
 
-    /// Parses a multiplication expression in the input.
-    /// A multiplication expression has the syntax:
+    /// Parses a postfix expression in the input.
+    /// A postfix expression has the syntax:
     /// 
     /// ```txt
-    /// mul:
+    /// postfix:
     /// 1.  base
-    /// 2.  base '*' mul
-    /// 3.  base '/' mul
-    /// 4.  base '%' mul
+    /// 2.  postfix '!'
     /// ```
-    fn parse_mul(&mut self) -> ParseResult {
-        // Parse left hand side, returning error or absent results
+    fn postfix_rules(&mut self) -> ParseResult {
+        // Parse the operand, returning error or absent results
         // immediately
-        let lhs = match self.parse_base() {
+        let mut exp = match self.parse_base() {
             Present(x) => x,
             other => return other
         };
 
+        // Rule 2, any amount of '!' may follow. Each one applies to
+        // everything before it, so 3!! is (3!)!.
+        while self.symbol() == Some('!') {
+            if let Some(err) = self.count_node() {
+                return err;
+            }
 
-        // Determine which operator was used to determine
-        // which syntax rule to apply
-        let op = match self.symbol() {
-            // Rule 2
-            Some('*') => Mul,
+            self.skip();
+            exp = factorial(exp);
+        }
 
-            // Rule 3
-            Some('/') => Div,
+        // Rule 1
+        Present(exp)
+    }
 
-            // Rule 4
-            Some('%') => Rem,
+    /// Parses a power expression in the input.
+    /// A power expression has the syntax:
+    /// 
+    /// ```txt
+    /// pow:
+    /// 1.  postfix
+    /// 2.  postfix '^' pow
+    /// ```
+    fn pow_rules(&mut self) -> ParseResult {
+        // Parse left hand side, returning error or absent results
+        // immediately
+        let lhs = match self.parse_postfix() {
+            Present(x) => x,
+            other => return other
+        };
+
+        // Rule 1, no operator; in that case, just return
+        if self.symbol() != Some('^') {
+            return Present(lhs);
+        }
 
-            // Rule 1; in that case, just return
-            // from the function already
-            _ => return Present(lhs)
+        // Rule 2
+        if let Some(err) = self.count_node() {
+            return err;
+        }
+
+        // Unlike the other operators, powers group from right to left:
+        // 2^3^2 is 2^(3^2). That's exactly what we get by recursing into
+        // parse_pow for the right hand side.
+        match self.skip().parse_pow() {
+            Present(rhs) => Present(pow(lhs, rhs)),
+            Absent => Error(ParseError::ExpectedExpression(self.here())),
+            other => other
+        }
+    }
+
+    /// Parses a multiplication expression in the input.
+    /// A multiplication expression has the syntax:
+    /// 
+    /// ```txt
+    /// mul:
+    /// 1.  pow
+    /// 2.  mul '*' pow
+    /// 3.  mul '/' pow
+    /// 4.  mul '%' pow
+    /// ```
+    fn mul_rules(&mut self) -> ParseResult {
+        // Parse left hand side, returning error or absent results
+        // immediately
+        let mut lhs = match self.parse_pow() {
+            Present(x) => x,
+            other => return other
         };
 
-        // Now we have eliminated rule 1, all other rules
-        // are the same logic, just different operators:
-        let right = self.skip().parse_mul();
+        // Like in parse_add, we loop so that the operators group from left
+        // to right: 16/2/2 is (16/2)/2, not 16/(2/2).
+        loop {
+            // Determine which operator was used to determine
+            // which syntax rule to apply
+            let op = match self.symbol() {
+                // Rule 2
+                Some('*') => Mul,
 
-        right.map(lhs, |p, rhs| {
-            match op {
-                Mul => mul(p, rhs),
-                Div => div(p, rhs),
-                Rem => rem(p, rhs)
+                // Rule 3
+                Some('/') => Div,
+
+                // Rule 4
+                Some('%') => Rem,
+
+                // Rule 1, or the end of a chain of operators; in
+                // that case, just return from the function already
+                _ => return Present(lhs)
+            };
+
+            if let Some(err) = self.count_node() {
+                return err;
             }
-        })
+
+            // All other rules are the same logic, just different operators.
+            // After an operator, there must be a right hand side.
+            let rhs = match self.skip().parse_pow() {
+                Present(x) => x,
+                Absent => return Error(ParseError::ExpectedExpression(self.here())),
+                other => return other
+            };
+
+            lhs = match op {
+                Mul => mul(lhs, rhs),
+                Div => div(lhs, rhs),
+                Rem => rem(lhs, rhs)
+            };
+        }
     }
 
     /// Parses a addition expression in the input.
@@ -388,42 +1102,54 @@ impl<'str> Parser<'str> {
     /// ```txt
     /// add:
     /// 1.  mul
-    /// 2.  mul '+' add
-    /// 3.  mul '-' add
+    /// 2.  add '+' mul
+    /// 3.  add '-' mul
     /// ```
-    fn parse_add(&mut self) -> ParseResult {
+    fn add_rules(&mut self) -> ParseResult {
         // Parse left hand side, returning error or absent results
         // immediately
-        let lhs = match self.parse_mul() {
+        let mut lhs = match self.parse_mul() {
             Present(x) => x,
             other => return other
         };
 
-
-        // Determine which operator was used to determine
-        // which syntax rule to apply
-        let op = match self.symbol() {
-            // Rule 2
-            Some('+') => Add,
-
-            // Rule 3
-            Some('-') => Sub,
-
-            // Rule 1; in that case, just return
-            // from the function already
-            _ => return Present(lhs)
-        };
-
-        // Now we have eliminated rule 1, all other rules
-        // are the same logic, just different operators:
-        let right = self.skip().parse_add();
-
-        right.map(lhs, |p, rhs| {
-            match op {
-                Add => add(p, rhs),
-                Sub => sub(p, rhs)
+        // Rules 2 and 3 start with another addition expression, so we can't
+        // simply recurse into parse_add at the start, that would never end.
+        // Instead, we keep reading an operator and a right hand side, and join
+        // them with the expression we have so far. This way, the operators group
+        // from left to right: 10-3-2 is (10-3)-2, not 10-(3-2).
+        loop {
+            // Determine which operator was used to determine
+            // which syntax rule to apply
+            let op = match self.symbol() {
+                // Rule 2
+                Some('+') => Add,
+
+                // Rule 3
+                Some('-') => Sub,
+
+                // Rule 1, or the end of a chain of operators; in
+                // that case, just return from the function already
+                _ => return Present(lhs)
+            };
+
+            if let Some(err) = self.count_node() {
+                return err;
             }
-        })
+
+            // All other rules are the same logic, just different operators.
+            // After an operator, there must be a right hand side.
+            let rhs = match self.skip().parse_mul() {
+                Present(x) => x,
+                Absent => return Error(ParseError::ExpectedExpression(self.here())),
+                other => return other
+            };
+
+            lhs = match op {
+                Add => add(lhs, rhs),
+                Sub => sub(lhs, rhs)
+            };
+        }
     }
 }
 
@@ -435,10 +1161,24 @@ fn _is_space(c: Option<char>) -> bool {
     }
 }
 
-/// Returns true if the given [Option] holds a digit or a period.
-fn _is_number_char(c: Option<char>) -> bool {
+/// Returns true if the given [Option] holds an ASCII digit or the given decimal
+/// separator.
+fn _is_number_char(c: Option<char>, decimal: char) -> bool {
+    // Note that we don't use 'is_numeric', that's true for digits of any numeral
+    // system (like the Arabic-Indic '٣') and for characters like '½', but Rust can
+    // only parse ASCII digits as a float. Those characters are rejected with a
+    // clear error instead, see _is_foreign_digit.
+    match c {
+        None => false,
+        Some(ch) => ch.is_ascii_digit() || ch == decimal
+    }
+}
+
+/// Returns true if the given [Option] holds a numeric character that is not an ASCII
+/// digit.
+fn _is_foreign_digit(c: Option<char>) -> bool {
     match c {
         None => false,
-        Some(ch) => ch.is_numeric() || ch == '.'
+        Some(ch) => ch.is_numeric() && !ch.is_ascii_digit()
     }
 }
\ No newline at end of file
@@ -0,0 +1,235 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::expression::*;
+
+/// The largest exponent that [Polynomial::from_expression] expands. Larger powers
+/// quickly get too many terms to be readable.
+pub const MAX_EXPONENT: f64 = 16.0;
+
+/// The largest amount of terms that [Polynomial::from_expression] expands to. Only
+/// capping the exponents isn't enough, powers of powers like `((a+b+c)^16)^16` get
+/// more terms than we can compute in any reasonable time.
+pub const MAX_TERMS: usize = 1000;
+
+/// The largest degree of a term that [Polynomial::from_expression] expands to. The
+/// term count doesn't catch powers of powers of a single variable, like
+/// `((x^16)^16)^16`, which stay one term while the power grows past what a `u32` holds.
+pub const MAX_DEGREE: u32 = 1000;
+
+/// A polynomial, which is a sum of terms like `3 * x ^ 2 * y`. Each term is a
+/// coefficient times a product of variables raised to positive integer powers.
+pub struct Polynomial {
+    // The key of a term is a list of variables with their power, sorted by name. The
+    // constant term has an empty list. Keeping the list sorted means that 'x * y' and
+    // 'y * x' get the same key, so like terms end up in the same entry.
+    terms: BTreeMap<Vec<(String, u32)>, f64>
+}
+
+impl Polynomial {
+    /// A polynomial that's just a constant.
+    fn constant(c: f64) -> Polynomial {
+        let mut terms = BTreeMap::new();
+        terms.insert(Vec::new(), c);
+        Polynomial { terms }.cleaned()
+    }
+
+    /// A polynomial that's just a variable.
+    fn variable(name: &str) -> Polynomial {
+        let mut terms = BTreeMap::new();
+        terms.insert(vec![(String::from(name), 1)], 1.0);
+        Polynomial { terms }
+    }
+
+    /// Removes terms whose coefficient became zero.
+    fn cleaned(mut self) -> Polynomial {
+        self.terms.retain(|_, c| *c != 0.0);
+        self
+    }
+
+    /// The sum of two polynomials. Like terms are collected by adding their coefficients.
+    fn add(mut self, other: Polynomial) -> Polynomial {
+        for (key, c) in other.terms {
+            *self.terms.entry(key).or_insert(0.0) += c;
+        }
+        self.cleaned()
+    }
+
+    /// The polynomial with all coefficients multiplied by a number.
+    fn scale(mut self, f: f64) -> Polynomial {
+        for c in self.terms.values_mut() {
+            *c *= f;
+        }
+        self.cleaned()
+    }
+
+    /// The product of two polynomials, which multiplies every term of one with every
+    /// term of the other. Gives an error once the product has more than [MAX_TERMS]
+    /// terms, or a term of a degree above [MAX_DEGREE].
+    fn mul(&self, other: &Polynomial) -> Result<Polynomial, String> {
+        let mut res = Polynomial { terms: BTreeMap::new() };
+        for (a_key, a_c) in &self.terms {
+            for (b_key, b_c) in &other.terms {
+                // Both terms are at most MAX_DEGREE, so adding their powers can't
+                // overflow, and checking the result keeps it that way
+                let key = _mul_keys(a_key, b_key);
+                if _degree(&key) > MAX_DEGREE {
+                    return Err(format!("Can't expand, because it has a term of a degree above {}", MAX_DEGREE));
+                }
+                *res.terms.entry(key).or_insert(0.0) += a_c * b_c;
+            }
+
+            // Stop as soon as we know it's too large. Terms that cancel out later are
+            // still counted, but that's rare enough not to matter.
+            if res.terms.len() > MAX_TERMS {
+                return Err(format!("Can't expand, because it has more than {} terms", MAX_TERMS));
+            }
+        }
+        Ok(res.cleaned())
+    }
+
+    /// The polynomial raised to a power, by repeated multiplication.
+    fn pow(&self, n: u32) -> Result<Polynomial, String> {
+        let mut res = Polynomial::constant(1.0);
+        for _ in 0..n {
+            res = res.mul(self)?;
+        }
+        Ok(res)
+    }
+
+    /// Expands an expression into a polynomial. This only works for expressions made
+    /// of `+`, `-`, `*`, division by constants and powers with small integer exponents,
+    /// and only if it gets at most [MAX_TERMS] terms of a degree up to [MAX_DEGREE]. Otherwise, this gives a note
+    /// explaining why it can't be expanded.
+    pub fn from_expression(exp: &dyn Expression) -> Result<Polynomial, String> {
+        // Anything without variables is a constant, which is also where we end up for
+        // numbers
+        if exp.is_constant() {
+            return match exp.eval() {
+                Ok(n) => Ok(Polynomial::constant(n)),
+                Err(x) => Err(format!("Can't expand, because {}", x))
+            };
+        }
+
+        if let Some(Variable(name)) = exp.as_any().downcast_ref::<Variable>() {
+            return Ok(Polynomial::variable(name));
+        }
+
+        let op = match exp.as_any().downcast_ref::<Operator>() {
+            Some(op) => op,
+            None => return Err(String::from("Can only expand operators"))
+        };
+
+        match op {
+            Operator::Add(left, right) => {
+                Ok(Polynomial::from_expression(&**left)?.add(Polynomial::from_expression(&**right)?))
+            },
+            Operator::Sub(left, right) => {
+                Ok(Polynomial::from_expression(&**left)?.add(Polynomial::from_expression(&**right)?.scale(-1.0)))
+            },
+            Operator::Mul(left, right) => {
+                Polynomial::from_expression(&**left)?.mul(&Polynomial::from_expression(&**right)?)
+            },
+            Operator::Neg(exp) => {
+                Ok(Polynomial::from_expression(&**exp)?.scale(-1.0))
+            },
+
+            // Dividing by a constant is multiplying by its inverse, dividing by a
+            // variable gives a negative power, and that's no polynomial
+            Operator::Div(left, right) => match right.is_constant().then(|| right.eval()) {
+                Some(Ok(d)) if d != 0.0 => Ok(Polynomial::from_expression(&**left)?.scale(1.0 / d)),
+                _ => Err(String::from("Can only expand a division by a constant that isn't zero"))
+            },
+
+            Operator::Pow(left, right) => match right.is_constant().then(|| right.eval()) {
+                Some(Ok(n)) if (0.0..=MAX_EXPONENT).contains(&n) && n.fract() == 0.0 => {
+                    Polynomial::from_expression(&**left)?.pow(n as u32)
+                },
+                _ => Err(format!("Can only expand powers with an integer exponent from 0 to {}", MAX_EXPONENT))
+            },
+
+            _ => Err(String::from("Can only expand +, -, *, / and ^"))
+        }
+    }
+
+    /// Turns the polynomial back into an expression. Terms of a higher degree come
+    /// first, so `(x + 1) ^ 2` becomes `x ^ 2 + 2 * x + 1`, and `(x + y) ^ 2` becomes
+    /// `x ^ 2 + 2 * x * y + y ^ 2`.
+    pub fn to_expression(&self) -> Box<dyn Expression> {
+        let mut terms: Vec<_> = self.terms.iter().collect();
+        terms.sort_by(|(a, _), (b, _)| _term_order(a, b));
+
+        let mut res: Option<Box<dyn Expression>> = None;
+        for (key, c) in terms {
+            res = Some(match res {
+                // The first term carries its own sign
+                None => _term(key, *c),
+
+                // Further terms are added, or subtracted when they're negative, so
+                // that we get 'x - 1' rather than 'x + -1'
+                Some(exp) if *c < 0.0 => sub(exp, _term(key, -c)),
+                Some(exp) => add(exp, _term(key, *c))
+            });
+        }
+
+        // No terms at all means all of them cancelled out
+        res.unwrap_or_else(|| val(0.0))
+    }
+}
+
+/// Multiplies two terms by merging their lists of variables, adding the powers of the
+/// variables they share. Both lists are sorted by name, and so is the result.
+fn _mul_keys(a: &[(String, u32)], b: &[(String, u32)]) -> Vec<(String, u32)> {
+    let mut res: Vec<(String, u32)> = a.to_vec();
+    for (name, p) in b {
+        match res.binary_search_by(|(n, _)| n.cmp(name)) {
+            Ok(i) => res[i].1 += p,
+            Err(i) => res.insert(i, (name.clone(), *p))
+        }
+    }
+    res
+}
+
+/// The degree of a term, which is the sum of the powers of its variables.
+fn _degree(key: &[(String, u32)]) -> u32 {
+    key.iter().map(|(_, p)| p).sum()
+}
+
+/// The order in which terms are written: higher degrees first, and within the same
+/// degree, higher powers of the variable that comes first in the alphabet. So the
+/// terms of `(x + y) ^ 3` are ordered `x ^ 3`, `x ^ 2 * y`, `x * y ^ 2`, `y ^ 3`.
+fn _term_order(a: &[(String, u32)], b: &[(String, u32)]) -> Ordering {
+    _degree(b).cmp(&_degree(a)).then_with(|| {
+        for ((a_name, a_p), (b_name, b_p)) in a.iter().zip(b) {
+            // A variable that isn't in a term has power 0 there, so the term that has
+            // the first variable in the alphabet has the higher power of it
+            let ord = a_name.cmp(b_name).then(b_p.cmp(a_p));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        Ordering::Equal
+    })
+}
+
+/// Creates the expression of one term, like `3 * x ^ 2 * y`.
+fn _term(key: &[(String, u32)], c: f64) -> Box<dyn Expression> {
+    let mut factors = key.iter().map(|(name, p)| match p {
+        1 => var(name),
+        _ => pow(var(name), val(*p as f64))
+    });
+
+    // Leave out a coefficient of 1, so that we get 'x' instead of '1 * x'
+    let mut res = match factors.next() {
+        None => return val(c),
+        Some(first) if c == 1.0 => first,
+        Some(first) if c == -1.0 => neg(first),
+        Some(first) => mul(val(c), first)
+    };
+
+    // Multiply from left to right, so that no parentheses are needed
+    for factor in factors {
+        res = mul(res, factor);
+    }
+    res
+}
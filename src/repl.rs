@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+
+use crate::expression::*;
+use crate::parser::*;
+use crate::polynomial::Polynomial;
+
+/// The state of the REPL. Commands, which are input lines starting with a ':',
+/// change this state.
+pub struct Repl {
+    /// The prompt that is printed before every input line.
+    pub prompt: String,
+
+    /// Whether the REPL prints each parsed expression before its result.
+    pub echo: bool,
+
+    /// Whether the REPL prints where NaN comes from when a result is NaN.
+    pub trace: bool,
+
+    /// Whether results are printed exactly as Rust prints them, without any of the
+    /// formatting the REPL does.
+    raw: bool,
+
+    /// Whether the parser prints its steps.
+    debug: bool,
+
+    /// What the parser does with calls to unknown functions.
+    unknown_call: UnknownCall,
+
+    /// How numbers are written, in input and in results.
+    locale: Locale,
+
+    /// What results are printed as.
+    units: Units,
+
+    /// The macros defined with ':macro', which are expanded in the input before parsing.
+    macros: Vec<Macro>,
+
+    /// The values of variables. This only has 'ans', the last result, once there is one.
+    env: HashMap<String, f64>,
+
+    /// The inputs of all successful evaluations so far.
+    inputs: Vec<String>,
+
+    /// The results of all successful evaluations so far, in the same order as the
+    /// inputs. These can be referred to as `$1`, `$2`, etc.
+    results: Vec<f64>,
+}
+
+/// What the REPL prints results as. This only changes how results are printed, not
+/// how they're computed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Units {
+    /// Plain numbers.
+    None,
+
+    /// Durations, where the result is a number of seconds, like `1h 2m 3s`.
+    Time,
+}
+
+/// A text macro, defined like `sq(a) = (a)*(a)`.
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: String
+}
+
+/// The prompt the REPL starts with.
+const DEFAULT_PROMPT: &str = ">>> ";
+
+/// How many times macros are expanded in a line, at most. Macros can use other macros,
+/// or even themselves, so without a limit a macro could expand forever.
+const MAX_MACRO_DEPTH: usize = 32;
+
+/// How long a line may get by expanding macros, in bytes. Limiting the depth isn't
+/// enough: a macro that uses itself twice, like `f(a) = f(a)+f(a)`, doubles the line
+/// on every pass, and 32 doublings don't fit in memory.
+const MAX_MACRO_LENGTH: usize = 100_000;
+
+impl Default for Repl {
+    /// Creates a REPL state with the default settings.
+    fn default() -> Repl {
+        Repl {
+            prompt: String::from(DEFAULT_PROMPT),
+            echo: false,
+            trace: true,
+            raw: false,
+            debug: false,
+            unknown_call: UnknownCall::Error,
+            locale: Locale::En,
+            units: Units::None,
+            macros: Vec::new(),
+            env: HashMap::new(),
+            inputs: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+}
+
+impl Repl {
+    /// The options for parsing input in the current state.
+    pub fn parse_options(&self) -> ParseOptions<'_> {
+        ParseOptions {
+            results: &self.results,
+            debug: self.debug,
+            unknown_call: self.unknown_call,
+            locale: self.locale,
+            ..ParseOptions::default()
+        }
+    }
+
+    /// How numbers are written, in input and in results.
+    pub fn locale(&self) -> Locale {
+        self.locale
+    }
+
+    /// The values of the variables that expressions are evaluated with.
+    pub fn env(&self) -> &HashMap<String, f64> {
+        &self.env
+    }
+
+    /// Remembers the input and result of a successful evaluation, so it can be
+    /// referred to later, as `ans` or as its `$N`.
+    pub fn record(&mut self, input: &str, result: f64) {
+        self.inputs.push(String::from(input.trim()));
+        self.results.push(result);
+        self.env.insert(String::from("ans"), result);
+    }
+
+    /// Formats a result for printing.
+    pub fn format_result(&self, n: f64) -> String {
+        // Rust prints floats with the shortest amount of digits that parse back to
+        // the same float, so this shows the full precision. Any formatting of the REPL
+        // goes below this.
+        if self.raw {
+            return n.to_string();
+        }
+
+        // Numbers that can't be a duration are printed as numbers
+        if self.units == Units::Time {
+            if let Some(st) = self.format_duration(n) {
+                return st;
+            }
+        }
+
+        // Floats have a negative zero, which is equal to zero but prints as -0. That's
+        // confusing, so we print it as 0.
+        if n == 0.0 {
+            return String::from("0");
+        }
+
+        self.format_number(n)
+    }
+
+    /// Formats a number like it's written in the input.
+    fn format_number(&self, n: f64) -> String {
+        let st = n.to_string();
+        match self.locale {
+            Locale::En => st,
+            Locale::Eu => st.replace('.', ",")
+        }
+    }
+
+    /// Formats a number of seconds as a duration, like `1h 2m 3.5s`. Parts that are zero
+    /// are left out, so 3600 is `1h`, and a negative duration gets a '-' in front. Gives
+    /// [None] for infinity, NaN, and durations of 2^53 milliseconds or more.
+    fn format_duration(&self, n: f64) -> Option<String> {
+        // Work in whole milliseconds. Dividing a float by 60 and taking the remainder
+        // leaves rounding errors, which would show up as seconds like 1.0999999999999943.
+        // Milliseconds are integers, and floats are exact for those below 2^53. Above
+        // that, or when multiplying overflows to infinity, the parts would be wrong.
+        let ms = (n.abs() * 1000.0).round();
+        if !ms.is_finite() || ms >= (1u64 << 53) as f64 {
+            return None;
+        }
+        let hours = (ms / 3_600_000.0).floor();
+        let minutes = (ms % 3_600_000.0 / 60_000.0).floor();
+        let seconds = ms % 60_000.0 / 1000.0;
+
+        let mut parts = Vec::new();
+        if hours != 0.0 {
+            parts.push(format!("{}h", hours));
+        }
+        if minutes != 0.0 {
+            parts.push(format!("{}m", minutes));
+        }
+        if seconds != 0.0 || parts.is_empty() {
+            parts.push(format!("{}s", self.format_number(seconds)));
+        }
+
+        // Something that rounds to zero milliseconds has no sign
+        let sign = if n < 0.0 && ms != 0.0 { "-" } else { "" };
+        Some(format!("{}{}", sign, parts.join(" ")))
+    }
+
+    /// Runs a command. The given string is the input line without the leading ':'.
+    /// Returns an error message if the command is invalid.
+    pub fn command(&mut self, cmd: &str) -> Result<(), String> {
+        // Split off the name of the command, the rest are its arguments
+        let cmd = cmd.trim();
+        let (name, arg) = match cmd.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (cmd, "")
+        };
+
+        match name {
+            "prompt" => {
+                // Without an argument, go back to the default. An empty prompt can
+                // still be set with ':prompt ""'.
+                self.prompt = if arg.is_empty() {
+                    String::from(DEFAULT_PROMPT)
+                } else {
+                    _unquote(arg)
+                };
+                Ok(())
+            },
+
+            "raw" => {
+                self.raw = _on_off(arg)?;
+                Ok(())
+            },
+
+            "debug" => {
+                self.debug = _on_off(arg)?;
+                Ok(())
+            },
+
+            "echo" => {
+                self.echo = _on_off(arg)?;
+                Ok(())
+            },
+
+            "trace" => {
+                self.trace = _on_off(arg)?;
+                Ok(())
+            },
+
+            "unknowncall" => {
+                self.unknown_call = match arg {
+                    "error" => UnknownCall::Error,
+                    "multiply" => UnknownCall::Multiply,
+                    _ => return Err(String::from("Expected 'error' or 'multiply'"))
+                };
+                Ok(())
+            },
+
+            "locale" => {
+                self.locale = match arg {
+                    "en" => Locale::En,
+                    "eu" => Locale::Eu,
+                    _ => return Err(String::from("Expected 'en' or 'eu'"))
+                };
+                Ok(())
+            },
+
+            "units" => {
+                self.units = match arg {
+                    "none" => Units::None,
+                    "time" => Units::Time,
+                    _ => return Err(String::from("Expected 'none' or 'time'"))
+                };
+                Ok(())
+            },
+
+            "macro" => {
+                if arg.is_empty() {
+                    for m in &self.macros {
+                        let sep = format!("{} ", self.locale.argument_separator());
+                        println!("    {}({}) = {}", m.name, m.params.join(&sep), m.body);
+                    }
+                    return Ok(());
+                }
+
+                let m = self.parse_macro(arg)?;
+
+                // Redefining a macro replaces it
+                self.macros.retain(|other| other.name != m.name);
+                self.macros.push(m);
+                Ok(())
+            },
+
+            "exact" => {
+                if self.parse_arg(arg)?.is_exact() {
+                    println!("<<< exact");
+                } else {
+                    println!("<<< may be rounded");
+                }
+                Ok(())
+            },
+
+            "simplify" => {
+                println!("<<< {}", self.parse_arg(arg)?.simplify().infix(self.locale));
+                Ok(())
+            },
+
+            "expand" => {
+                let exp = self.parse_arg(arg)?;
+                match Polynomial::from_expression(&*exp) {
+                    Ok(poly) => println!("<<< {}", poly.to_expression().infix(self.locale)),
+
+                    // Print it as it is, with the reason why
+                    Err(note) => {
+                        println!("<<< {}", exp.infix(self.locale));
+                        println!("    {}", note);
+                    }
+                }
+                Ok(())
+            },
+
+            "profile" => {
+                let mut profile = Profile::default();
+                let res = self.parse_arg(arg)?.eval_profiled(&self.env, &mut profile).map_err(_runtime_error)?;
+
+                println!("<<< {}", self.format_result(res));
+                println!("    {}", profile);
+                Ok(())
+            },
+
+            "bits" => {
+                let res = self.parse_arg(arg)?.eval_in(&self.env).map_err(_runtime_error)?;
+
+                println!("<<< {}", self.format_result(res));
+                println!("    {}", _format_bits(res));
+                Ok(())
+            },
+
+            "results" => {
+                for (i, (input, result)) in self.inputs.iter().zip(&self.results).enumerate() {
+                    println!("${:<4} {} = {}", i + 1, input, self.format_result(*result));
+                }
+                Ok(())
+            },
+
+            _ => Err(format!("Unknown command ':{}'", name))
+        }
+    }
+
+    /// Parses the definition of a macro, like `sq(a) = (a)*(a)`.
+    fn parse_macro(&self, def: &str) -> Result<Macro, String> {
+        let (head, body) = def.split_once('=')
+            .ok_or_else(|| String::from("Expected '=' after the name of the macro"))?;
+
+        let (name, params) = head.trim()
+            .strip_suffix(')')
+            .and_then(|head| head.split_once('('))
+            .ok_or_else(|| String::from("Expected a name with parameters, like 'sq(a)'"))?;
+
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|ch| ch.is_ascii_alphabetic()) {
+            return Err(String::from("The name of a macro can only have letters"));
+        }
+
+        // No parameters at all is fine, like 'two() = 2'
+        let mut names: Vec<String> = Vec::new();
+        if !params.trim().is_empty() {
+            for param in params.split(self.locale.argument_separator()) {
+                let param = param.trim();
+                if param.is_empty() || !param.chars().all(|ch| ch.is_ascii_alphabetic()) {
+                    return Err(String::from("The parameters of a macro can only have letters"));
+                }
+                if names.iter().any(|other| other == param) {
+                    return Err(format!("Parameter '{}' appears twice", param));
+                }
+                names.push(String::from(param));
+            }
+        }
+
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(String::from("Expected the body of the macro after '='"));
+        }
+
+        Ok(Macro { name: String::from(name), params: names, body: String::from(body) })
+    }
+
+    /// Expands all macros in an input line. This is purely textual: a call like
+    /// `sq(3+1)` is replaced by the body of the macro, with every parameter replaced
+    /// by its argument in parentheses, like `((3+1))*((3+1))`. The result of a macro
+    /// is not put in parentheses, that's up to its body.
+    pub fn expand_macros(&self, line: &str) -> Result<String, String> {
+        let mut line = String::from(line);
+
+        // The body of a macro can call macros too, so keep going until nothing
+        // changes anymore
+        for _ in 0..MAX_MACRO_DEPTH {
+            match self.expand_once(&line)? {
+                Some(expanded) => line = expanded,
+                None => return Ok(line)
+            }
+        }
+        Err(String::from("Macros expand too deeply, does one of them use itself?"))
+    }
+
+    /// Expands all macro calls in a line once, without expanding the result again.
+    /// Gives [None] if there are no calls.
+    fn expand_once(&self, line: &str) -> Result<Option<String>, String> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut res = String::new();
+        let mut changed = false;
+
+        let mut i = 0;
+        while i < chars.len() {
+            if !chars[i].is_ascii_alphabetic() {
+                res.push(chars[i]);
+                i += 1;
+                continue;
+            }
+
+            // Read a whole name, like the parser does: a letter followed by letters
+            // and digits. Digits before it aren't part of a name, but letters and
+            // digits after it are, so 'a1sq(2)' doesn't call 'sq'.
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+
+            // Only a name followed by '(' is a call
+            let mut open = i;
+            while open < chars.len() && (chars[open] == ' ' || chars[open] == '\t') {
+                open += 1;
+            }
+
+            let m = self.macros.iter().find(|m| m.name == name);
+            match m {
+                Some(m) if chars.get(open) == Some(&'(') => {
+                    let (args, end) = self.macro_args(&chars, open, m)?;
+                    res.push_str(&_substitute(&m.body, &m.params, &args));
+                    changed = true;
+                    i = end;
+
+                    // Check as we go, the line may get too long in one pass already
+                    if res.len() > MAX_MACRO_LENGTH {
+                        return Err(String::from("Macros expand to a line that is too long, does one of them use itself?"));
+                    }
+                },
+                _ => res.push_str(&name)
+            }
+        }
+
+        Ok(if changed { Some(res) } else { None })
+    }
+
+    /// Reads the arguments of a call to a macro. The character at `open` is the '('.
+    /// Returns the arguments, and the index right after the closing ')'.
+    fn macro_args(&self, chars: &[char], open: usize, m: &Macro) -> Result<(Vec<String>, usize), String> {
+        let separator = self.locale.argument_separator();
+        let mut args = Vec::new();
+        let mut arg = String::new();
+        let mut depth = 0;
+
+        for (i, ch) in chars.iter().enumerate().skip(open + 1) {
+            match ch {
+                // Only a separator or ')' that isn't between inner parentheses ends
+                // an argument
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' => {
+                    // An empty list of arguments is no arguments
+                    if !(args.is_empty() && arg.trim().is_empty() && m.params.is_empty()) {
+                        args.push(arg);
+                    }
+
+                    if args.len() != m.params.len() {
+                        let plural = if m.params.len() == 1 { "" } else { "s" };
+                        return Err(format!("Macro '{}' takes {} argument{}", m.name, m.params.len(), plural));
+                    }
+                    return Ok((args, i + 1));
+                },
+                ch if *ch == separator && depth == 0 => {
+                    args.push(arg);
+                    arg = String::new();
+                    continue;
+                },
+                _ => {}
+            }
+            arg.push(*ch);
+        }
+
+        Err(format!("Expected ')' after the arguments of macro '{}'", m.name))
+    }
+
+    /// Parses the argument of a command that takes an expression.
+    fn parse_arg(&self, arg: &str) -> Result<Box<dyn Expression>, String> {
+        let arg = self.expand_macros(arg)?;
+        match parse_with(&arg, &self.parse_options()) {
+            ParseResult::Present(exp) => Ok(exp),
+            ParseResult::Absent => Err(String::from("Expected an expression")),
+            ParseResult::Error(x) => Err(format!("{}, at index {}", x, char_index(&arg, x.span().start)))
+        }
+    }
+}
+
+/// Replaces every parameter in the body of a macro by its argument, in parentheses.
+/// Only whole names are replaced, so a parameter 'a' doesn't replace the 'a' in 'tan'.
+fn _substitute(body: &str, params: &[String], args: &[String]) -> String {
+    let mut res = String::new();
+    let mut name = String::new();
+
+    // Writes the name that was just read, or the argument if it's a parameter
+    let flush = |res: &mut String, name: &mut String| {
+        match params.iter().position(|param| param == name) {
+            Some(i) => res.push_str(&format!("({})", args[i].trim())),
+            None => res.push_str(name)
+        }
+        name.clear();
+    };
+
+    // Names are read like the parser does, so a digit only continues a name that
+    // started with a letter
+    for ch in body.chars() {
+        if ch.is_ascii_alphabetic() || (ch.is_ascii_digit() && !name.is_empty()) {
+            name.push(ch);
+        } else {
+            flush(&mut res, &mut name);
+            res.push(ch);
+        }
+    }
+    flush(&mut res, &mut name);
+    res
+}
+
+/// Formats a syntax error in an input line as two lines: the input, and carets under
+/// the characters that the error is about, followed by the message. Both lines start
+/// with '!!! ', so the input and carets line up.
+pub fn format_parse_error(input: &str, x: &ParseError) -> String {
+    let input = input.trim_end_matches(['\n', '\r']);
+    let span = x.span();
+
+    // Copy the tabs in front of the span, so that the carets end up in the same column
+    // however wide a tab is shown. The span is in bytes, but we need a space for
+    // every character.
+    let pad: String = input[..span.start].chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    // An empty span, like at the end of the input, still gets one caret
+    let carets = "^".repeat(input[span].chars().count().max(1));
+
+    format!("!!! {}\n!!! {}{} {}", input, pad, carets, x)
+}
+
+/// The index of the character at a byte index in a string. Spans of errors and warnings
+/// are byte indices, but for people, characters are what count.
+pub fn char_index(input: &str, byte: usize) -> usize {
+    input[..byte].chars().count()
+}
+
+/// Formats an evaluation error like the REPL does.
+fn _runtime_error(x: EvalError) -> String {
+    format!("runtime error: {}", x)
+}
+
+/// Formats the IEEE-754 bit pattern of a number, along with the three parts it consists
+/// of: 1 sign bit, 11 exponent bits, and 52 mantissa bits.
+fn _format_bits(n: f64) -> String {
+    let bits = n.to_bits();
+
+    let sign = bits >> 63;
+    let exponent = (bits >> 52) & 0x7FF;
+    let mantissa = bits & ((1 << 52) - 1);
+
+    // The exponent is stored with a bias of 1023, except for the lowest and highest
+    // exponent, which mean zero or subnormal, and infinity or NaN
+    let meaning = match exponent {
+        0 => String::from("zero or subnormal"),
+        0x7FF => String::from("infinity or NaN"),
+        e => format!("2^{}", e as i64 - 1023)
+    };
+
+    format!(
+        "0x{:016X}: sign {}, exponent 0x{:03X} ({}), mantissa 0x{:013X}",
+        bits, sign, exponent, meaning, mantissa
+    )
+}
+
+/// Parses the argument of a command that turns a setting on or off.
+fn _on_off(arg: &str) -> Result<bool, String> {
+    match arg {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(String::from("Expected 'on' or 'off'"))
+    }
+}
+
+/// Removes the surrounding double quotes of a command argument, if it has them.
+/// Quotes are needed to have a trailing space in the argument.
+fn _unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => String::from(inner),
+        None => String::from(s)
+    }
+}